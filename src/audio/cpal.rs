@@ -0,0 +1,84 @@
+//! Output through cpal with the software mixer, no OpenAL needed
+
+use audio::mixer::{lock, MixerBackend};
+use audio::{AudioBackend, SampleId, Voice};
+use cpal::{self, EventLoop, StreamData, UnknownTypeOutputBuffer};
+use std::path::Path;
+use std::thread;
+use ::errors::KeyboardError;
+
+/// Plays on the default output device of the system
+pub struct CpalBackend {
+    mixer: MixerBackend,
+}
+
+impl CpalBackend {
+    /// Starts an output stream that plays the mixer until the program
+    /// exits
+    pub fn new() -> Result<CpalBackend, KeyboardError>
+    {
+        let device = try!(cpal::default_output_device()
+            .ok_or(KeyboardError::Audio("No output device".into())));
+        let format = try!(device.default_output_format()
+            .map_err(|error| KeyboardError::Audio(format!("Unable to get output format: {:?}", error))));
+
+        let event_loop = EventLoop::new();
+        let stream = try!(event_loop.build_output_stream(&device, &format)
+            .map_err(|error| KeyboardError::Audio(format!("Unable to open output: {:?}", error))));
+        event_loop.play_stream(stream);
+        info!("Playing on {} at {} Hz with {} channels",
+              device.name(), format.sample_rate.0, format.channels);
+
+        let backend = MixerBackend::new(format.sample_rate.0);
+        let mixer = backend.mixer();
+        let channels = format.channels as usize;
+
+        thread::spawn(move || {
+            // frames for outputs that don't take f32 samples, only
+            // allocated when the buffers grow
+            let mut frames = vec![];
+
+            // the mixer is only locked while mixing
+            event_loop.run(move |_, data| {
+                let buffer = match data {
+                    StreamData::Output { buffer } => buffer,
+                    _ => return,
+                };
+
+                match buffer {
+                    UnknownTypeOutputBuffer::F32(mut buffer) => lock(&mixer).mix(&mut buffer, channels),
+                    UnknownTypeOutputBuffer::I16(mut buffer) => {
+                        frames.resize(buffer.len(), 0.0);
+                        lock(&mixer).mix(&mut frames, channels);
+                        for (out, sample) in buffer.iter_mut().zip(&frames) {
+                            *out = (sample * ::std::i16::MAX as f32) as i16;
+                        }
+                    },
+                    UnknownTypeOutputBuffer::U16(mut buffer) => {
+                        frames.resize(buffer.len(), 0.0);
+                        lock(&mixer).mix(&mut frames, channels);
+                        for (out, sample) in buffer.iter_mut().zip(&frames) {
+                            *out = ((sample + 1.0) / 2.0 * ::std::u16::MAX as f32) as u16;
+                        }
+                    },
+                }
+            })
+        });
+
+        Ok(CpalBackend { mixer: backend })
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn load(&mut self, path: &Path) -> Result<SampleId, KeyboardError> {
+        self.mixer.load(path)
+    }
+
+    fn voice(&mut self, sample: SampleId) -> Result<Box<dyn Voice>, KeyboardError> {
+        self.mixer.voice(sample)
+    }
+
+    fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]) {
+        self.mixer.set_listener(position, at, up)
+    }
+}
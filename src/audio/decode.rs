@@ -0,0 +1,256 @@
+//! Decoding sound files
+//!
+//! Every backend loads samples through `decode`, so a pack plays the
+//! same everywhere no matter which formats the system libraries know.
+//! WAV, FLAC, OGG/Vorbis and MP3 files are decoded by their extension.
+
+use claxon;
+use hound;
+use lewton::inside_ogg::OggStreamReader;
+use minimp3;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use ::errors::KeyboardError;
+
+/// Decoded samples of a sound file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pcm {
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// Interleaved samples in [-1.0, 1.0]
+    pub data: Vec<f32>,
+}
+
+impl Pcm {
+    /// Mixes all channels down to one
+    pub fn to_mono(self) -> Pcm {
+        if self.channels <= 1 {
+            return self;
+        }
+
+        let channels = self.channels;
+        let data = self.data.chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        Pcm { channels: 1, data: data, .. self }
+    }
+}
+
+
+/// Decodes the sound file at `path` by its extension
+pub fn decode(path: &Path) -> Result<Pcm, KeyboardError> {
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+    let pcm = match extension.as_ref().map(|extension| &**extension) {
+        Some("wav") => try!(decode_wav(path)),
+        Some("flac") => try!(decode_flac(path)),
+        Some("ogg") | Some("oga") => try!(decode_ogg(path)),
+        Some("mp3") => try!(decode_mp3(path)),
+        _ => return Err(KeyboardError::Audio(format!(
+            "Unable to decode {:?}, only wav, flac, ogg and mp3 files are supported", path))),
+    };
+
+    if pcm.channels == 0 || pcm.sample_rate == 0 || pcm.data.is_empty() {
+        return Err(KeyboardError::Audio(format!("Unable to decode {:?}: no samples", path)));
+    }
+    Ok(pcm)
+}
+
+
+/// Writes `data`, interleaved with `channels` samples per frame, to a
+/// 16 bit WAV file
+pub fn write_wav(path: &Path, data: &[f32], channels: usize, sample_rate: u32) -> Result<(), KeyboardError> {
+    let mut file = try!(WavFile::create(path, channels, sample_rate));
+    try!(file.write(data));
+    file.finalize()
+}
+
+
+/// A 16 bit WAV file written a block of samples at a time
+pub struct WavFile {
+    path: PathBuf,
+    writer: hound::WavWriter<BufWriter<File>>,
+}
+
+impl WavFile {
+    /// Creates the file at `path` for frames of `channels` samples
+    pub fn create(path: &Path, channels: usize, sample_rate: u32) -> Result<WavFile, KeyboardError> {
+        let spec = hound::WavSpec {
+            channels: channels as u16,
+            sample_rate: sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+
+        let writer = try!(hound::WavWriter::create(path, spec).map_err(write_error(path)));
+        Ok(WavFile { path: path.to_path_buf(), writer: writer })
+    }
+
+    /// Appends interleaved samples in [-1.0, 1.0]
+    pub fn write(&mut self, data: &[f32]) -> Result<(), KeyboardError> {
+        for sample in data {
+            try!(self.writer.write_sample((sample.max(-1.0).min(1.0) * ::std::i16::MAX as f32) as i16)
+                 .map_err(write_error(&self.path)));
+        }
+        Ok(())
+    }
+
+    /// Completes the header, the file is unreadable without it
+    pub fn finalize(self) -> Result<(), KeyboardError> {
+        let path = self.path;
+        self.writer.finalize().map_err(write_error(&path))
+    }
+}
+
+
+fn write_error<'a>(path: &'a Path) -> impl Fn(hound::Error) -> KeyboardError + 'a {
+    move |error| KeyboardError::Audio(format!("Unable to write {:?}: {}", path, error))
+}
+
+
+/// Returns a function that turns a decoder error into an error naming
+/// the file
+fn error<'a, E: Display>(path: &'a Path) -> impl Fn(E) -> KeyboardError + 'a {
+    move |error| KeyboardError::Audio(format!("Unable to decode {:?}: {}", path, error))
+}
+
+
+fn decode_wav(path: &Path) -> Result<Pcm, KeyboardError> {
+    let mut reader = try!(hound::WavReader::open(path).map_err(error(path)));
+    let spec = reader.spec();
+    let data = match spec.sample_format {
+        hound::SampleFormat::Float => try!(reader.samples::<f32>().collect::<Result<Vec<_>, _>>()
+            .map_err(error(path))),
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            try!(reader.samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(error(path)))
+        },
+    };
+
+    Ok(Pcm { sample_rate: spec.sample_rate, channels: spec.channels as usize, data: data })
+}
+
+
+fn decode_flac(path: &Path) -> Result<Pcm, KeyboardError> {
+    let mut reader = try!(claxon::FlacReader::open(path).map_err(error(path)));
+    let info = reader.streaminfo();
+    let scale = (1u64 << (info.bits_per_sample - 1)) as f32;
+    let data = try!(reader.samples()
+        .map(|sample| sample.map(|sample| sample as f32 / scale))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(error(path)));
+
+    Ok(Pcm { sample_rate: info.sample_rate, channels: info.channels as usize, data: data })
+}
+
+
+fn decode_ogg(path: &Path) -> Result<Pcm, KeyboardError> {
+    let file = try!(File::open(path).map_err(error(path)));
+    let mut reader = try!(OggStreamReader::new(file).map_err(error(path)));
+
+    let mut data = vec![];
+    while let Some(packet) = try!(reader.read_dec_packet_itl().map_err(error(path))) {
+        data.extend(packet.into_iter().map(|sample| sample as f32 / 32768.0));
+    }
+
+    Ok(Pcm {
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        channels: reader.ident_hdr.audio_channels as usize,
+        data: data,
+    })
+}
+
+
+fn decode_mp3(path: &Path) -> Result<Pcm, KeyboardError> {
+    let file = try!(File::open(path).map_err(error(path)));
+    let mut decoder = minimp3::Decoder::new(file);
+
+    let mut pcm = Pcm { sample_rate: 0, channels: 0, data: vec![] };
+    loop {
+        let frame = match decoder.next_frame() {
+            Ok(frame) => frame,
+            Err(minimp3::Error::Eof) => break,
+            Err(minimp3::Error::SkippedData) => continue,
+            Err(error) => return Err(KeyboardError::Audio(format!(
+                "Unable to decode {:?}: {}", path, error))),
+        };
+
+        // frames can differ in format, the first one counts
+        if pcm.channels == 0 {
+            pcm.sample_rate = frame.sample_rate as u32;
+            pcm.channels = frame.channels;
+        }
+        if frame.channels != pcm.channels || frame.sample_rate as u32 != pcm.sample_rate {
+            warn!("Skipping a frame of {:?} with another format", path);
+            continue;
+        }
+        pcm.data.extend(frame.data.into_iter().map(|sample| sample as f32 / 32768.0));
+    }
+    Ok(pcm)
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{decode, write_wav, Pcm};
+    use std::path::{Path, PathBuf};
+    use std::{env, fs, process};
+
+    /// Short tones in `resources/test`
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test").join(name)
+    }
+
+    fn assert_near(pcm: &Pcm, index: usize, sample: f32) {
+        assert!((pcm.data[index] - sample).abs() < 0.001, "{} at {} is not {}", pcm.data[index], index, sample);
+    }
+
+    #[test]
+    fn decode_wav_OK() -> () {
+        let path = env::temp_dir().join(format!("modelm-decode-{}.WAV", process::id()));
+        write_wav(&path, &[0.5, -0.5, 0.25, 0.0], 2, 22050).unwrap();
+        let pcm = decode(&path);
+        fs::remove_file(&path).unwrap();
+
+        let pcm = pcm.unwrap();
+        assert_eq!((pcm.sample_rate, pcm.channels), (22050, 2));
+        assert_near(&pcm, 0, 0.5);
+        assert_near(&pcm, 1, -0.5);
+        assert_eq!(pcm.to_mono().data.len(), 2);
+
+        assert!(decode(&env::temp_dir().join("modelm-decode.aiff")).is_err());
+        assert!(decode(&env::temp_dir().join("modelm-missing.flac")).is_err());
+    }
+
+    #[test]
+    fn decode_flac_OK() -> () {
+        let pcm = decode(&fixture("click.flac")).unwrap();
+        assert_eq!((pcm.sample_rate, pcm.channels, pcm.data.len()), (22050, 2, 128));
+        assert_near(&pcm, 0, 0.5);
+        assert_near(&pcm, 1, -0.25);
+        assert_near(&pcm, 2, 0.4961);
+    }
+
+    #[test]
+    fn decode_ogg_OK() -> () {
+        let pcm = decode(&fixture("click.ogg")).unwrap();
+        assert_eq!((pcm.sample_rate, pcm.channels, pcm.data.len()), (8000, 1, 1408));
+        assert_near(&pcm, 0, -0.1868);
+        assert_near(&pcm, 1, -0.2040);
+    }
+
+    #[test]
+    fn decode_mp3_OK() -> () {
+        let pcm = decode(&fixture("click.mp3")).unwrap();
+        assert_eq!((pcm.sample_rate, pcm.channels, pcm.data.len()), (32000, 1, 12 * 1152));
+        // the decoder starts out silent
+        assert_eq!(pcm.data[0], 0.0);
+        assert_near(&pcm, 6912, 0.1990);
+    }
+}
@@ -0,0 +1,353 @@
+//! Software mixing
+//!
+//! Mixes voices into frames for outputs that don't mix by themselves.
+//! Like OpenAL, a voice is panned by where it is relative to the
+//! listener, gets quieter farther away (inverse distance clamped at 1.0)
+//! and is resampled to change its pitch.
+//!
+//! Outputs mix on a realtime thread while holding the lock of the Mixer,
+//! and mixing allocates nothing. Voices keep their settings and check or
+//! stop playing without the lock. They take it to start playing, to
+//! change settings while playing, and when created or dropped, which
+//! also grows or frees the channels of the Mixer.
+
+use audio::decode::{decode, Pcm};
+use audio::{AudioBackend, SampleId, Voice};
+use std::f32::consts::FRAC_PI_4;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use ::errors::KeyboardError;
+
+/// Distance from the listener up to which voices play at full volume
+const REFERENCE_DISTANCE: f32 = 1.0;
+
+
+/// A voice of the mixer
+struct Channel {
+    sample: SampleId,
+    /// Frame of the sample to play next, between frames when resampling
+    cursor: f64,
+    /// Shared with the voice, which reads and stops it without locking
+    playing: Arc<AtomicBool>,
+    position: [f32; 3],
+    pitch: f32,
+    volume: f32,
+}
+
+
+/// Mixes the voices playing mono samples
+pub struct Mixer {
+    sample_rate: u32,
+    samples: Vec<Arc<Pcm>>,
+    channels: Vec<Option<Channel>>,
+    listener: ([f32; 3], [f32; 3], [f32; 3]),
+}
+
+impl Mixer {
+    /// Creates a Mixer that mixes at `sample_rate`
+    pub fn new(sample_rate: u32) -> Mixer
+    {
+        Mixer {
+            sample_rate: sample_rate,
+            samples: vec![],
+            channels: vec![],
+            listener: ([0.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Adds a sample, mixed down to mono so it can be panned
+    pub fn add_sample(&mut self, pcm: Pcm) -> SampleId {
+        self.samples.push(Arc::new(pcm.to_mono()));
+        self.samples.len() - 1
+    }
+
+    /// Places the listener, facing `at` with `up` above
+    pub fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]) {
+        self.listener = (position, at, up);
+    }
+
+    /// Returns true if any voice is playing
+    pub fn is_playing(&self) -> bool {
+        self.channels.iter().any(|channel| channel.as_ref().map_or(false, |channel| channel.playing.load(Ordering::SeqCst)))
+    }
+
+    /// Overwrites `out` with the next frames of the playing voices,
+    /// interleaved with `channels` samples per frame. Only the first two
+    /// channels of a frame get sound.
+    pub fn mix(&mut self, out: &mut [f32], channels: usize) {
+        for sample in out.iter_mut() {
+            *sample = 0.0;
+        }
+        if channels == 0 {
+            return;
+        }
+
+        let sample_rate = self.sample_rate as f64;
+        let listener = self.listener;
+        for channel in self.channels.iter_mut() {
+            let channel = match channel.as_mut() {
+                Some(channel) if channel.playing.load(Ordering::SeqCst) => channel,
+                _ => continue,
+            };
+            let (left, right) = gains(listener, channel);
+            let pcm = &self.samples[channel.sample];
+            let step = channel.pitch.max(0.0) as f64 * pcm.sample_rate as f64 / sample_rate;
+
+            for frame in out.chunks_mut(channels) {
+                let index = channel.cursor as usize;
+                if index >= pcm.data.len() {
+                    channel.playing.store(false, Ordering::SeqCst);
+                    break;
+                }
+
+                // linear interpolation between the frames around the cursor
+                let fraction = (channel.cursor - index as f64) as f32;
+                let next = pcm.data.get(index + 1).cloned().unwrap_or(0.0);
+                let value = pcm.data[index] * (1.0 - fraction) + next * fraction;
+
+                match frame.len() {
+                    1 => frame[0] += value * (left + right) / 2.0,
+                    _ => {
+                        frame[0] += value * left;
+                        frame[1] += value * right;
+                    },
+                }
+                channel.cursor += step;
+            }
+        }
+
+        for sample in out.iter_mut() {
+            *sample = sample.max(-1.0).min(1.0);
+        }
+    }
+
+    fn add_channel(&mut self, sample: SampleId) -> Result<(usize, Arc<AtomicBool>), KeyboardError> {
+        if sample >= self.samples.len() {
+            return Err(KeyboardError::Audio(format!("No sample {}", sample)));
+        }
+
+        let playing = Arc::new(AtomicBool::new(false));
+        let channel = Channel {
+            sample: sample,
+            cursor: 0.0,
+            playing: playing.clone(),
+            position: [0.0; 3],
+            pitch: 1.0,
+            volume: 1.0,
+        };
+        match self.channels.iter().position(|channel| channel.is_none()) {
+            Some(index) => {
+                self.channels[index] = Some(channel);
+                Ok((index, playing))
+            },
+            None => {
+                self.channels.push(Some(channel));
+                Ok((self.channels.len() - 1, playing))
+            },
+        }
+    }
+}
+
+
+/// Returns the left and right gain of a channel heard by `listener`
+fn gains(listener: ([f32; 3], [f32; 3], [f32; 3]), channel: &Channel) -> (f32, f32) {
+    let (listener, at, up) = listener;
+    let offset = sub(channel.position, listener);
+    let distance = dot(offset, offset).sqrt();
+    let attenuation = REFERENCE_DISTANCE
+        / (REFERENCE_DISTANCE + (distance.max(REFERENCE_DISTANCE) - REFERENCE_DISTANCE));
+
+    let right = cross(at, up);
+    let right_length = dot(right, right).sqrt();
+    let pan = match distance > 0.0 && right_length > 0.0 {
+        true => (dot(offset, right) / (distance * right_length)).max(-1.0).min(1.0),
+        false => 0.0,
+    };
+
+    // equal power panning
+    let angle = (pan + 1.0) * FRAC_PI_4;
+    let gain = channel.volume * attenuation;
+    (gain * angle.cos(), gain * angle.sin())
+}
+
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+
+/// Decodes samples and plays them on a Mixer, which an output pulls
+/// frames from
+pub struct MixerBackend {
+    mixer: Arc<Mutex<Mixer>>,
+}
+
+impl MixerBackend {
+    pub fn new(sample_rate: u32) -> MixerBackend
+    {
+        MixerBackend { mixer: Arc::new(Mutex::new(Mixer::new(sample_rate))) }
+    }
+
+    /// Returns the mixer to pull frames from
+    pub fn mixer(&self) -> Arc<Mutex<Mixer>> {
+        self.mixer.clone()
+    }
+}
+
+impl AudioBackend for MixerBackend {
+    fn load(&mut self, path: &Path) -> Result<SampleId, KeyboardError> {
+        let pcm = try!(decode(path));
+        Ok(lock(&self.mixer).add_sample(pcm))
+    }
+
+    fn voice(&mut self, sample: SampleId) -> Result<Box<dyn Voice>, KeyboardError> {
+        let (channel, playing) = try!(lock(&self.mixer).add_channel(sample));
+        Ok(Box::new(MixerVoice::new(self.mixer.clone(), channel, playing)))
+    }
+
+    fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]) {
+        lock(&self.mixer).set_listener(position, at, up);
+    }
+}
+
+
+/// Locks the mixer, even if an output panicked while holding it
+pub fn lock<'a>(mixer: &'a Mutex<Mixer>) -> MutexGuard<'a, Mixer> {
+    mixer.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+
+/// A channel of a Mixer, freed when dropped. Keeps the settings of the
+/// channel to hand them over in one go when it starts playing.
+struct MixerVoice {
+    mixer: Arc<Mutex<Mixer>>,
+    channel: usize,
+    playing: Arc<AtomicBool>,
+    position: [f32; 3],
+    pitch: f32,
+    volume: f32,
+}
+
+impl MixerVoice {
+    fn new(mixer: Arc<Mutex<Mixer>>, channel: usize, playing: Arc<AtomicBool>) -> MixerVoice
+    {
+        MixerVoice {
+            mixer: mixer,
+            channel: channel,
+            playing: playing,
+            position: [0.0; 3],
+            pitch: 1.0,
+            volume: 1.0,
+        }
+    }
+
+    /// Passes the settings to the channel
+    fn update(&self, mixer: &mut Mixer) {
+        if let Some(channel) = mixer.channels[self.channel].as_mut() {
+            channel.position = self.position;
+            channel.pitch = self.pitch;
+            channel.volume = self.volume;
+        }
+    }
+
+    /// Passes the settings to the channel if it is playing, otherwise
+    /// `play` does
+    fn update_playing(&self) {
+        if self.is_playing() {
+            self.update(&mut lock(&self.mixer));
+        }
+    }
+}
+
+impl Voice for MixerVoice {
+    fn play(&mut self) {
+        let mut mixer = lock(&self.mixer);
+        self.update(&mut mixer);
+        if let Some(channel) = mixer.channels[self.channel].as_mut() {
+            channel.cursor = 0.0;
+        }
+        self.playing.store(true, Ordering::SeqCst);
+    }
+
+    fn stop(&mut self) {
+        self.playing.store(false, Ordering::SeqCst)
+    }
+
+    fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::SeqCst)
+    }
+
+    fn set_position(&mut self, position: [f32; 3]) {
+        self.position = position;
+        self.update_playing();
+    }
+
+    fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch;
+        self.update_playing();
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        self.update_playing();
+    }
+}
+
+impl Drop for MixerVoice {
+    fn drop(&mut self) {
+        lock(&self.mixer).channels[self.channel] = None;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{Mixer, MixerVoice};
+    use audio::Voice;
+    use audio::decode::Pcm;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn mixer_pan_pitch_OK() -> () {
+        let mut mixer = Mixer::new(100);
+        let sample = mixer.add_sample(Pcm { sample_rate: 100, channels: 2, data: vec![0.5; 60] });
+        let (channel, playing) = mixer.add_channel(sample).unwrap();
+        let mixer = Arc::new(Mutex::new(mixer));
+        let mut voice = MixerVoice::new(mixer.clone(), channel, playing);
+
+        // to the right of the listener, twice as fast
+        voice.set_position([1.0, 0.0, 0.0]);
+        voice.set_pitch(2.0);
+        voice.play();
+
+        // 30 frames at twice the speed last 15 frames
+        let mut out = vec![0.0; 20];
+        mixer.lock().unwrap().mix(&mut out, 2);
+        assert!(out[1] > 0.49 && out[19] > 0.49 && out[0].abs() < 1e-6);
+        assert!(voice.is_playing());
+
+        mixer.lock().unwrap().mix(&mut out, 2);
+        assert!(out[9] > 0.49 && out[11] == 0.0);
+        assert!(!voice.is_playing());
+        mixer.lock().unwrap().mix(&mut out, 2);
+        assert_eq!(out, vec![0.0; 20]);
+
+        drop(voice);
+        assert!(mixer.lock().unwrap().channels[channel].is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! Recording output for tests
+
+use audio::{AudioBackend, SampleId, Voice};
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+use std::rc::Rc;
+use ::errors::KeyboardError;
+
+/// Something a MockBackend was asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockEvent {
+    /// A sample was loaded, by file name
+    Load(String),
+    /// A voice started playing a sample
+    Play { sample: String, position: [f32; 3], pitch: f32, volume: f32 },
+    /// A playing voice was stopped
+    Stop(String),
+    Listener { position: [f32; 3], at: [f32; 3], up: [f32; 3] },
+}
+
+/// Records everything it is asked to do instead of playing it. Voices
+/// play until they are stopped.
+///
+/// # Example
+/// ```ignore
+/// let backend = MockBackend::new();
+/// let log = backend.log();
+/// let keyboard = Keyboard::new().with_backend(Box::new(backend));
+/// // ... handle events
+/// assert_eq!(log.borrow()[0], MockEvent::Load("down_1.wav".into()));
+/// ```
+pub struct MockBackend {
+    log: Rc<RefCell<Vec<MockEvent>>>,
+    samples: Vec<String>,
+    /// How many voices can exist at once, like OpenAL's sources
+    max_voices: Option<usize>,
+    voices: Rc<Cell<usize>>,
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend
+    {
+        MockBackend {
+            log: Rc::new(RefCell::new(vec![])),
+            samples: vec![],
+            max_voices: None,
+            voices: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Fails to create more than `max_voices` voices at once
+    pub fn with_max_voices(mut self, max_voices: usize) -> MockBackend
+    {
+        self.max_voices = Some(max_voices);
+        self
+    }
+
+    /// Returns the log of events, which stays readable after the backend
+    /// is handed to a Keyboard
+    pub fn log(&self) -> Rc<RefCell<Vec<MockEvent>>> {
+        self.log.clone()
+    }
+}
+
+impl AudioBackend for MockBackend {
+    fn load(&mut self, path: &Path) -> Result<SampleId, KeyboardError> {
+        let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+        self.log.borrow_mut().push(MockEvent::Load(name.clone()));
+        self.samples.push(name);
+        Ok(self.samples.len() - 1)
+    }
+
+    fn voice(&mut self, sample: SampleId) -> Result<Box<dyn Voice>, KeyboardError> {
+        if self.max_voices.map_or(false, |max_voices| self.voices.get() >= max_voices) {
+            return Err(KeyboardError::Audio("out of voices".into()));
+        }
+        let sample = try!(self.samples.get(sample)
+            .ok_or(KeyboardError::Audio(format!("No sample {}", sample))));
+
+        self.voices.set(self.voices.get() + 1);
+        Ok(Box::new(MockVoice {
+            sample: sample.clone(),
+            log: self.log.clone(),
+            voices: self.voices.clone(),
+            playing: false,
+            position: [0.0; 3],
+            pitch: 1.0,
+            volume: 1.0,
+        }))
+    }
+
+    fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]) {
+        self.log.borrow_mut().push(MockEvent::Listener { position: position, at: at, up: up });
+    }
+}
+
+
+struct MockVoice {
+    sample: String,
+    log: Rc<RefCell<Vec<MockEvent>>>,
+    /// Voices of the backend that exist
+    voices: Rc<Cell<usize>>,
+    playing: bool,
+    position: [f32; 3],
+    pitch: f32,
+    volume: f32,
+}
+
+impl Voice for MockVoice {
+    fn play(&mut self) {
+        self.playing = true;
+        self.log.borrow_mut().push(MockEvent::Play {
+            sample: self.sample.clone(),
+            position: self.position,
+            pitch: self.pitch,
+            volume: self.volume,
+        });
+    }
+
+    fn stop(&mut self) {
+        if self.playing {
+            self.playing = false;
+            self.log.borrow_mut().push(MockEvent::Stop(self.sample.clone()));
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    fn set_position(&mut self, position: [f32; 3]) {
+        self.position = position;
+    }
+
+    fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch;
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+}
+
+impl Drop for MockVoice {
+    fn drop(&mut self) {
+        self.voices.set(self.voices.get() - 1);
+    }
+}
@@ -0,0 +1,100 @@
+//! Audio output
+//!
+//! Switches don't talk to an audio library directly. They load samples
+//! and play them on voices of an `AudioBackend`, so the keyboard can be
+//! tested without a sound device.
+//!
+//! * `OpenAlBackend` plays through OpenAL with the `ears` crate, with
+//!   the `openal` feature (default)
+//! * `CpalBackend` mixes in software and plays through cpal, with the
+//!   `cpal` feature
+//! * `MockBackend` records what would be played, for tests
+//! * `NullBackend` plays nothing
+
+use std::path::Path;
+use ::errors::KeyboardError;
+
+pub mod decode;
+pub mod mixer;
+pub mod mock;
+pub mod null;
+#[cfg(feature = "openal")]
+pub mod openal;
+#[cfg(feature = "cpal")]
+pub mod cpal;
+
+pub use self::mixer::{Mixer, MixerBackend};
+pub use self::mock::{MockBackend, MockEvent};
+pub use self::null::NullBackend;
+#[cfg(feature = "openal")]
+pub use self::openal::OpenAlBackend;
+#[cfg(feature = "cpal")]
+pub use self::cpal::CpalBackend;
+
+/// Identifies a sample loaded by a backend
+pub type SampleId = usize;
+
+/// Plays one sample at a time
+pub trait Voice {
+    fn play(&mut self);
+    fn stop(&mut self);
+    fn is_playing(&self) -> bool;
+    fn set_position(&mut self, position: [f32; 3]);
+    fn set_pitch(&mut self, pitch: f32);
+    fn set_volume(&mut self, volume: f32);
+}
+
+/// Loads samples and creates voices to play them
+pub trait AudioBackend {
+    /// Decodes the sound file at `path`
+    fn load(&mut self, path: &Path) -> Result<SampleId, KeyboardError>;
+
+    /// Creates a voice that plays `sample`. Fails when the backend has
+    /// no voices left.
+    fn voice(&mut self, sample: SampleId) -> Result<Box<dyn Voice>, KeyboardError>;
+
+    /// Places the listener, see `KeyboardOptions`
+    fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]);
+}
+
+
+/// Names of the backends that can be opened, the first is the default
+pub fn backends() -> Vec<&'static str> {
+    let mut backends = vec![];
+    if cfg!(feature = "openal") {
+        backends.push("openal");
+    }
+    if cfg!(feature = "cpal") {
+        backends.push("cpal");
+    }
+    backends.push("null");
+    backends
+}
+
+/// Opens the backend called `name`, one of `backends()`
+pub fn open(name: &str) -> Result<Box<dyn AudioBackend>, KeyboardError> {
+    match name {
+        #[cfg(feature = "openal")]
+        "openal" => {
+            try!(::ears::init().map_err(KeyboardError::Audio));
+            Ok(Box::new(OpenAlBackend::new()))
+        },
+        #[cfg(feature = "cpal")]
+        "cpal" => Ok(Box::new(try!(CpalBackend::new()))),
+        "null" => Ok(Box::new(NullBackend::new())),
+        _ => Err(KeyboardError::Config(format!(
+            "backend must be one of {}, not {}", backends().join(", "), name))),
+    }
+}
+
+/// The backend of a new Keyboard, OpenAL if it is compiled in
+#[cfg(feature = "openal")]
+pub fn default_backend() -> Box<dyn AudioBackend> {
+    Box::new(OpenAlBackend::new())
+}
+
+/// The backend of a new Keyboard, OpenAL if it is compiled in
+#[cfg(not(feature = "openal"))]
+pub fn default_backend() -> Box<dyn AudioBackend> {
+    Box::new(NullBackend::new())
+}
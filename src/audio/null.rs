@@ -0,0 +1,42 @@
+//! Silent output
+
+use audio::{AudioBackend, SampleId, Voice};
+use std::path::Path;
+use ::errors::KeyboardError;
+
+/// Accepts every sample and plays nothing
+pub struct NullBackend {
+    samples: usize,
+}
+
+impl NullBackend {
+    pub fn new() -> NullBackend
+    {
+        NullBackend { samples: 0 }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn load(&mut self, _path: &Path) -> Result<SampleId, KeyboardError> {
+        self.samples += 1;
+        Ok(self.samples - 1)
+    }
+
+    fn voice(&mut self, _sample: SampleId) -> Result<Box<dyn Voice>, KeyboardError> {
+        Ok(Box::new(NullVoice))
+    }
+
+    fn set_listener(&mut self, _position: [f32; 3], _at: [f32; 3], _up: [f32; 3]) {}
+}
+
+
+struct NullVoice;
+
+impl Voice for NullVoice {
+    fn play(&mut self) {}
+    fn stop(&mut self) {}
+    fn is_playing(&self) -> bool { false }
+    fn set_position(&mut self, _position: [f32; 3]) {}
+    fn set_pitch(&mut self, _pitch: f32) {}
+    fn set_volume(&mut self, _volume: f32) {}
+}
@@ -0,0 +1,199 @@
+//! OpenAL output
+//!
+//! `ears` opens the device and places the listener, but only loads
+//! samples from files through libsndfile. Decoded samples go straight
+//! into OpenAL buffers instead, without a file in between.
+
+use audio::{decode, AudioBackend, SampleId, Voice};
+use ears::listener;
+use libc;
+use std::path::Path;
+use std::rc::Rc;
+use ::errors::KeyboardError;
+
+use self::al::*;
+
+/// Plays through OpenAL, `ears::init` has to be called first
+pub struct OpenAlBackend {
+    samples: Vec<Rc<Buffer>>,
+}
+
+impl OpenAlBackend {
+    pub fn new() -> OpenAlBackend
+    {
+        OpenAlBackend { samples: vec![] }
+    }
+}
+
+impl AudioBackend for OpenAlBackend {
+    fn load(&mut self, path: &Path) -> Result<SampleId, KeyboardError> {
+        let pcm = try!(decode::decode(path));
+        // OpenAL only takes mono and stereo buffers
+        let pcm = match pcm.channels {
+            1 | 2 => pcm,
+            _ => pcm.to_mono(),
+        };
+        let stereo = pcm.channels == 2;
+
+        let mut buffer = Buffer { id: 0 };
+        unsafe {
+            alGetError();
+            alGenBuffers(1, &mut buffer.id);
+        }
+        try!(check(&format!("Unable to load {:?}", path)));
+
+        // keep float samples as they are if OpenAL takes them
+        let float = unsafe { alIsExtensionPresent(b"AL_EXT_FLOAT32\0".as_ptr() as *const libc::c_char) } != 0;
+        unsafe {
+            if float {
+                let format = if stereo { AL_FORMAT_STEREO_FLOAT32 } else { AL_FORMAT_MONO_FLOAT32 };
+                alBufferData(buffer.id, format, pcm.data.as_ptr() as *const libc::c_void,
+                             (pcm.data.len() * 4) as ALsizei, pcm.sample_rate as ALsizei);
+            } else {
+                let format = if stereo { AL_FORMAT_STEREO16 } else { AL_FORMAT_MONO16 };
+                let data: Vec<i16> = pcm.data.iter()
+                    .map(|sample| (sample.max(-1.0).min(1.0) * ::std::i16::MAX as f32) as i16)
+                    .collect();
+                alBufferData(buffer.id, format, data.as_ptr() as *const libc::c_void,
+                             (data.len() * 2) as ALsizei, pcm.sample_rate as ALsizei);
+            }
+        }
+        try!(check(&format!("Unable to load {:?}", path)));
+
+        self.samples.push(Rc::new(buffer));
+        Ok(self.samples.len() - 1)
+    }
+
+    fn voice(&mut self, sample: SampleId) -> Result<Box<dyn Voice>, KeyboardError> {
+        let buffer = try!(self.samples.get(sample)
+            .ok_or(KeyboardError::Audio(format!("No sample {}", sample)))).clone();
+
+        let mut source = 0;
+        unsafe {
+            alGetError();
+            alGenSources(1, &mut source);
+        }
+        try!(check("Unable to create a voice"));
+
+        unsafe { alSourcei(source, AL_BUFFER, buffer.id as ALint) };
+        Ok(Box::new(OpenAlVoice { source: source, _buffer: buffer }))
+    }
+
+    fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]) {
+        listener::set_position(position);
+        listener::set_orientation(at, up);
+    }
+}
+
+
+/// Fails with `message` if the last OpenAL call failed
+fn check(message: &str) -> Result<(), KeyboardError> {
+    match unsafe { alGetError() } {
+        AL_NO_ERROR => Ok(()),
+        error => Err(KeyboardError::Audio(format!("{}: OpenAL error {:#x}", message, error))),
+    }
+}
+
+
+/// An OpenAL buffer, deleted once no voice plays it
+struct Buffer {
+    id: ALuint,
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { alDeleteBuffers(1, &self.id) }
+    }
+}
+
+
+/// An OpenAL source
+struct OpenAlVoice {
+    source: ALuint,
+    /// Outlives the source
+    _buffer: Rc<Buffer>,
+}
+
+impl Voice for OpenAlVoice {
+    fn play(&mut self) {
+        unsafe { alSourcePlay(self.source) }
+    }
+
+    fn stop(&mut self) {
+        unsafe { alSourceStop(self.source) }
+    }
+
+    fn is_playing(&self) -> bool {
+        let mut state = 0;
+        unsafe { alGetSourcei(self.source, AL_SOURCE_STATE, &mut state) };
+        state == AL_PLAYING
+    }
+
+    fn set_position(&mut self, position: [f32; 3]) {
+        unsafe { alSource3f(self.source, AL_POSITION, position[0], position[1], position[2]) }
+    }
+
+    fn set_pitch(&mut self, pitch: f32) {
+        unsafe { alSourcef(self.source, AL_PITCH, pitch) }
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        unsafe { alSourcef(self.source, AL_GAIN, volume) }
+    }
+}
+
+impl Drop for OpenAlVoice {
+    fn drop(&mut self) {
+        unsafe {
+            alSourceStop(self.source);
+            alDeleteSources(1, &self.source);
+        }
+    }
+}
+
+
+/// The parts of al.h the backend needs
+mod al {
+    #![allow(non_upper_case_globals, non_snake_case)]
+
+    use libc;
+
+    pub type ALuint = libc::c_uint;
+    pub type ALint = libc::c_int;
+    pub type ALenum = libc::c_int;
+    pub type ALsizei = libc::c_int;
+    pub type ALfloat = libc::c_float;
+    pub type ALboolean = libc::c_char;
+
+    pub const AL_NO_ERROR: ALenum = 0;
+    pub const AL_PITCH: ALenum = 0x1003;
+    pub const AL_POSITION: ALenum = 0x1004;
+    pub const AL_BUFFER: ALenum = 0x1009;
+    pub const AL_GAIN: ALenum = 0x100A;
+    pub const AL_SOURCE_STATE: ALenum = 0x1010;
+    pub const AL_PLAYING: ALint = 0x1012;
+    pub const AL_FORMAT_MONO16: ALenum = 0x1101;
+    pub const AL_FORMAT_STEREO16: ALenum = 0x1103;
+    // AL_EXT_FLOAT32
+    pub const AL_FORMAT_MONO_FLOAT32: ALenum = 0x10010;
+    pub const AL_FORMAT_STEREO_FLOAT32: ALenum = 0x10011;
+
+    #[cfg_attr(target_os = "macos", link(name = "OpenAL", kind = "framework"))]
+    #[cfg_attr(not(target_os = "macos"), link(name = "openal"))]
+    extern "C" {
+        pub fn alGetError() -> ALenum;
+        pub fn alIsExtensionPresent(name: *const libc::c_char) -> ALboolean;
+        pub fn alGenBuffers(n: ALsizei, buffers: *mut ALuint);
+        pub fn alDeleteBuffers(n: ALsizei, buffers: *const ALuint);
+        pub fn alBufferData(buffer: ALuint, format: ALenum, data: *const libc::c_void, size: ALsizei,
+                            freq: ALsizei);
+        pub fn alGenSources(n: ALsizei, sources: *mut ALuint);
+        pub fn alDeleteSources(n: ALsizei, sources: *const ALuint);
+        pub fn alSourcei(source: ALuint, param: ALenum, value: ALint);
+        pub fn alSourcef(source: ALuint, param: ALenum, value: ALfloat);
+        pub fn alSource3f(source: ALuint, param: ALenum, x: ALfloat, y: ALfloat, z: ALfloat);
+        pub fn alGetSourcei(source: ALuint, param: ALenum, value: *mut ALint);
+        pub fn alSourcePlay(source: ALuint);
+        pub fn alSourceStop(source: ALuint);
+    }
+}
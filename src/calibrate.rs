@@ -0,0 +1,213 @@
+//! Layout calibration
+//!
+//! Writes a layout for keyboards no builtin layout describes. The user
+//! presses every key of a row from left to right, pauses, and goes on
+//! with the next row, from the back row to the front row. Keys are one
+//! unit wide and rows start at the left edge.
+//!
+//! # Example
+//! ```ignore
+//! let layout = calibrate(NativeSource::new(), "Split")?;
+//! File::create("split.yaml")?.write_all(layout.as_bytes())?;
+//! ```
+
+use ffi::types::{EventType, Key, KeyEvent};
+use input::InputSource;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use ::errors::KeyboardError;
+
+/// A pause at least this long between two keys starts a new row
+pub const ROW_PAUSE_MS: u64 = 1500;
+
+/// Calibration of a live source ends after a pause this long
+pub const DONE_PAUSE_MS: u64 = 5000;
+
+/// Reads key presses from `source` and returns them as a layout file
+/// called `name`. Ends when the source does or, for live sources,
+/// after a pause of DONE_PAUSE_MS.
+pub fn calibrate<S: InputSource + 'static>(source: S, name: &str) -> Result<String, KeyboardError> {
+    calibrate_with_pause(source, name, Duration::from_millis(DONE_PAUSE_MS))
+}
+
+/// Like `calibrate`, but live sources end after a pause of `done_pause`
+pub fn calibrate_with_pause<S: InputSource + 'static>(mut source: S, name: &str, done_pause: Duration)
+                                                      -> Result<String, KeyboardError> {
+    let (tx, rx) = channel();
+    let live = source.is_live();
+    let stop = source.stop_handle();
+    let listener = thread::spawn(move || source.run(tx));
+
+    // wait for the first key before timing the pause
+    let mut presses: Vec<KeyEvent> = rx.recv().into_iter().collect();
+    loop {
+        let event = match rx.recv_timeout(done_pause) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if live => {
+                info!("No key pressed for {:?}, done", done_pause);
+                drop(rx);
+                match stop {
+                    // e.g. to let a terminal leave raw mode
+                    Some(stop) => {
+                        stop.stop();
+                        let _ = listener.join();
+                    },
+                    None => debug!("Leaving the source to stop on its next event"),
+                }
+                return Ok(write_layout(name, &rows(&presses)));
+            },
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        presses.push(event);
+    }
+
+    match listener.join() {
+        Ok(result) => try!(result),
+        Err(_) => return Err(KeyboardError::Listener("listener thread panicked".into())),
+    }
+    Ok(write_layout(name, &rows(&presses)))
+}
+
+/// Splits the key presses into rows at pauses of ROW_PAUSE_MS, every
+/// key is used once where it was first pressed
+fn rows(events: &[KeyEvent]) -> Vec<Vec<Key>> {
+    let mut rows: Vec<Vec<Key>> = vec![];
+    let mut seen = vec![];
+    let mut last_press: Option<Duration> = None;
+
+    for event in events {
+        match event.etype {
+            EventType::KeyDown | EventType::FlagsChanged => (),
+            _ => continue,
+        }
+        if event.key == Key::Unknown {
+            warn!("Skipping unknown key code {}", event.code);
+            continue;
+        }
+        if seen.contains(&event.key) {
+            continue;
+        }
+        seen.push(event.key);
+
+        let pause = last_press
+            .map(|last| event.time.checked_sub(last).unwrap_or(Duration::new(0, 0)));
+        last_press = Some(event.time);
+
+        match pause {
+            Some(pause) if pause < Duration::from_millis(ROW_PAUSE_MS) => (),
+            _ => {
+                info!("Row {}", rows.len() + 1);
+                rows.push(vec![]);
+            },
+        }
+        debug!("Key {}", event.key.name());
+        rows.last_mut().unwrap().push(event.key);
+    }
+    rows
+}
+
+/// Formats rows of keys as a layout file
+fn write_layout(name: &str, rows: &[Vec<Key>]) -> String {
+    let mut layout = format!("## Written by modelm calibrate\nname: {}\nrows:\n", name);
+    for row in rows {
+        let keys: Vec<&str> = row.iter().map(|key| key.name()).collect();
+        layout.push_str(&format!("  - [{}]\n", keys.join(", ")));
+    }
+    layout
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{calibrate, calibrate_with_pause};
+    use ffi::types::{EventType, Key, KeyEvent};
+    use input::{InputSource, StopHandle, VecSource};
+    use layout::Layout;
+    use session::ReplaySource;
+    use std::sync::mpsc::Sender;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use ::errors::KeyboardError;
+
+    /// Presses a key, then waits to be stopped like a terminal
+    struct StoppableSource {
+        stop: StopHandle,
+        stopped: Arc<Mutex<bool>>,
+    }
+
+    impl InputSource for StoppableSource {
+        fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+            let _ = tx.send(KeyEvent {
+                etype: EventType::KeyDown, code: 0, key: Key::Q, device: 0, time: Duration::new(0, 0)
+            });
+            while !self.stop.is_stopped() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            *self.stopped.lock().unwrap() = true;
+            Ok(())
+        }
+
+        fn is_live(&self) -> bool {
+            true
+        }
+
+        fn stop_handle(&self) -> Option<StopHandle> {
+            Some(self.stop.clone())
+        }
+    }
+
+    #[test]
+    fn calibrate_rows_OK() -> () {
+        let event = |etype, key: Key, ms| KeyEvent {
+            etype: etype, code: key.native_code().unwrap_or(0), key: key, device: 0,
+            time: Duration::from_millis(ms),
+        };
+        let source = VecSource::new(vec![
+            event(EventType::KeyDown, Key::Q, 0),
+            event(EventType::KeyUp, Key::Q, 50),
+            event(EventType::KeyDown, Key::W, 300),
+            event(EventType::KeyDown, Key::W, 600),
+            event(EventType::KeyDown, Key::A, 3000),
+            event(EventType::KeyDown, Key::Unknown, 3100),
+            event(EventType::KeyDown, Key::S, 3200),
+        ]);
+
+        let layout = calibrate(source, "Test").unwrap();
+        assert_eq!(layout, "## Written by modelm calibrate\nname: Test\nrows:\n  - [Q, W]\n  - [A, S]\n");
+
+        let layout = Layout::from_yaml_str(&layout).unwrap();
+        assert_eq!(layout.position(Key::S), Some((1.5, 1.5)));
+    }
+
+    #[test]
+    fn calibrate_stops_source_OK() -> () {
+        let stopped = Arc::new(Mutex::new(false));
+        let source = StoppableSource { stop: StopHandle::new(), stopped: stopped.clone() };
+
+        let started = Instant::now();
+        let layout = calibrate_with_pause(source, "Test", Duration::from_millis(50)).unwrap();
+        assert!(layout.ends_with("  - [Q]\n"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(*stopped.lock().unwrap());
+    }
+
+    #[test]
+    fn calibrate_replay_OK() -> () {
+        let log = "# modelm session
+100.0 KeyDown 16 0 Q
+100.1 KeyUp 16 0 Q
+100.4 KeyDown 17 0 W
+104.0 KeyDown 30 0 A
+104.3 KeyDown 31 0 S
+";
+        let started = Instant::now();
+        let layout = calibrate(ReplaySource::new(log.as_bytes()).untimed(), "Replayed").unwrap();
+        assert_eq!(layout, "## Written by modelm calibrate\nname: Replayed\nrows:\n  - [Q, W]\n  - [A, S]\n");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,209 @@
+//! Typing dynamics
+//!
+//! Makes clicks depend on how you type. Every key press gets a velocity
+//! from the time since the previous press, so fast bursts are lighter
+//! than a keystroke after a pause. The velocity sets the loudness of the
+//! click and which samples may play, and the time a key was held sets
+//! the loudness of its release.
+//!
+//! # Example config
+//! ```yaml
+//! dynamics:
+//!   ## ms since the previous keydown -> velocity
+//!   velocity: [[0, 0.4], [200, 0.7], [1000, 1.0]]
+//!   ## velocity -> gain
+//!   gain: [[0, 0.5], [1, 1.0]]
+//!   ## ms the key was held -> gain of the keyup sound
+//!   release: [[0, 1.0], [600, 0.6]]
+//! ```
+
+use std::time::Duration;
+use yaml_rust::Yaml;
+use ::errors::KeyboardError;
+
+/// A piecewise-linear function through a list of points, constant
+/// before the first and after the last point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    points: Vec<(f32, f32)>,
+}
+
+impl Curve {
+    /// A curve that is `y` everywhere
+    pub fn constant(y: f32) -> Curve
+    {
+        Curve { points: vec![(0.0, y)] }
+    }
+
+    /// A curve through `points`, which must be sorted by x
+    pub fn new(points: Vec<(f32, f32)>) -> Result<Curve, KeyboardError>
+    {
+        if points.is_empty() || points.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+            return Err(KeyboardError::Config(
+                "curves must have at least one point, sorted by x".into()));
+        }
+        Ok(Curve { points: points })
+    }
+
+    /// Parses a list of points like `[[0, 0.4], [200, 1.0]]`
+    pub fn from_yaml(yaml: &Yaml) -> Result<Curve, KeyboardError>
+    {
+        let error = || KeyboardError::Config(format!("Unable to parse curve: {:?}", yaml));
+        let number = |yaml: &Yaml| match *yaml {
+            Yaml::Real(_) => yaml.as_f64().map(|n| n as f32).ok_or_else(&error),
+            Yaml::Integer(n) => Ok(n as f32),
+            _ => Err(error()),
+        };
+
+        let points = try_yaml!(*yaml, Yaml::Array, "curves must be an Array of [x, y] points");
+        let mut curve = vec![];
+        for point in points {
+            match *point {
+                Yaml::Array(ref point) if point.len() == 2 =>
+                    curve.push((try!(number(&point[0])), try!(number(&point[1])))),
+                _ => return Err(error()),
+            }
+        }
+        Curve::new(curve)
+    }
+
+    /// Returns the value of the curve at `x`
+    pub fn at(&self, x: f32) -> f32 {
+        let first = self.points[0];
+        if x <= first.0 {
+            return first.1;
+        }
+
+        for pair in self.points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if x <= x1 {
+                return match x1 > x0 {
+                    true => y0 + (y1 - y0) * (x - x0) / (x1 - x0),
+                    false => y1,
+                };
+            }
+        }
+        self.points[self.points.len() - 1].1
+    }
+}
+
+
+/// How hard a key was hit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stroke {
+    /// From light (0.0) to hard (1.0)
+    pub velocity: f32,
+    /// Factor of the volume
+    pub gain: f32,
+}
+
+impl Default for Stroke {
+    fn default() -> Self
+    {
+        Stroke { velocity: 1.0, gain: 1.0 }
+    }
+}
+
+
+/// The curves of a pack that turn typing into Strokes
+#[derive(Debug, Clone)]
+pub struct Dynamics {
+    /// ms since the previous keydown to velocity
+    pub velocity: Curve,
+    /// velocity to gain
+    pub gain: Curve,
+    /// ms a key was held to gain of its keyup
+    pub release: Curve,
+}
+
+impl Default for Dynamics {
+    fn default() -> Self
+    {
+        Dynamics {
+            velocity: Curve::constant(1.0),
+            gain: Curve::constant(1.0),
+            release: Curve::constant(1.0),
+        }
+    }
+}
+
+impl Dynamics {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Dynamics, KeyboardError>
+    {
+        let mut dynamics = Dynamics::default();
+        try_yaml!(*yaml, Yaml::Hash, "config must have Hash [dynamics]");
+
+        if !yaml["velocity"].is_badvalue() {
+            dynamics.velocity = try!(Curve::from_yaml(&yaml["velocity"]));
+        }
+        if !yaml["gain"].is_badvalue() {
+            dynamics.gain = try!(Curve::from_yaml(&yaml["gain"]));
+        }
+        if !yaml["release"].is_badvalue() {
+            dynamics.release = try!(Curve::from_yaml(&yaml["release"]));
+        }
+        Ok(dynamics)
+    }
+
+    /// Returns the stroke of a keydown `interval` after the previous
+    /// one, None if it is the first
+    pub fn keydown(&self, interval: Option<Duration>) -> Stroke {
+        let velocity = match interval {
+            Some(interval) => self.velocity.at(millis(interval)),
+            None => self.velocity.at(::std::f32::INFINITY),
+        };
+        Stroke { velocity: velocity, gain: self.gain.at(velocity) }
+    }
+
+    /// Returns the stroke of releasing a key hit with `keydown` after
+    /// holding it for `held`
+    pub fn keyup(&self, keydown: Stroke, held: Duration) -> Stroke {
+        Stroke { gain: keydown.gain * self.release.at(millis(held)), .. keydown }
+    }
+}
+
+
+fn millis(duration: Duration) -> f32 {
+    duration.as_secs() as f32 * 1000.0 + duration.subsec_nanos() as f32 / 1_000_000.0
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{Curve, Dynamics};
+    use std::time::Duration;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn dynamics_curve_OK() -> () {
+        let curve = Curve::new(vec![(0.0, 0.5), (100.0, 1.0), (100.0, 0.0), (200.0, 1.0)]).unwrap();
+        assert_eq!(curve.at(-5.0), 0.5);
+        assert_eq!(curve.at(50.0), 0.75);
+        assert_eq!(curve.at(100.0), 1.0);
+        assert_eq!(curve.at(150.0), 0.5);
+        assert_eq!(curve.at(1000.0), 1.0);
+        assert!(Curve::new(vec![(1.0, 0.0), (0.0, 1.0)]).is_err());
+        assert!(Curve::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn dynamics_strokes_OK() -> () {
+        let yaml = &YamlLoader::load_from_str("
+velocity: [[0, 0.4], [200, 1.0]]
+gain: [[0, 0.5], [1, 1]]
+release: [[0, 1], [500, 0.5]]
+").unwrap()[0];
+        let dynamics = Dynamics::from_yaml(yaml).unwrap();
+
+        let burst = dynamics.keydown(Some(Duration::from_millis(50)));
+        assert!((burst.velocity - 0.55).abs() < 1e-6);
+        assert_eq!(dynamics.keydown(None).velocity, 1.0);
+        assert_eq!(dynamics.keydown(None).gain, 1.0);
+
+        let keyup = dynamics.keyup(dynamics.keydown(None), Duration::from_millis(1000));
+        assert_eq!((keyup.velocity, keyup.gain), (1.0, 0.5));
+        assert!(Dynamics::from_yaml(&YamlLoader::load_from_str("gain: 1").unwrap()[0]).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::io;
 use yaml_rust;
 use regex;
 
@@ -9,5 +10,11 @@ quick_error! {
         /// Config Error
         Config(err: String) { from() }
         Regex(err: regex::Error) { from() }
+        /// IO Error
+        Io(err: io::Error) { from() }
+        /// Input listener failed or stopped
+        Listener(err: String) {}
+        /// Audio backend failed
+        Audio(err: String)
     }
 }
@@ -1,10 +1,20 @@
 /// Linux key handler ffi
 
-use std::fs::File;
+use libc;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::{File, read_dir};
 use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
-use std::{mem, slice, io, env};
-use super::types::{EventType, KeyEvent};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{cmp, mem, ptr, slice, io, env, thread};
+use super::types::{DeviceId, EventType, Key, KeyEvent};
+use input::InputSource;
+use ::errors::KeyboardError;
 
 type LinuxEventCode = u16;
 type LinuxEventType = u16;
@@ -27,6 +37,30 @@ struct InputEvent {
     value: LinuxEventValue,
 }
 
+/// Directory scanned for evdev devices
+const INPUT_DIR: &'static str = "/dev/input";
+
+/// The EV_KEY event type, reported by devices with keys or buttons
+const EV_KEY: u32 = 1;
+
+/// Highest key code the kernel will report (KEY_MAX)
+const KEY_MAX: usize = 0x2ff;
+
+/// Delay before the first attempt to reopen a device after an error
+const RETRY_DELAY_MS: u64 = 100;
+
+/// Upper bound for the delay between attempts to reopen a device
+const MAX_RETRY_DELAY_MS: u64 = 5000;
+
+/// Attempts to reopen a device before it is detached
+const MAX_RETRIES: u32 = 10;
+
+/// Codes for KEY_A through KEY_Z
+const LETTER_KEYS: [LinuxEventCode; 26] = [
+    30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50,
+    49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44,
+];
+
 /// Returns true if the code is considered a "flag", i.e. a modifier
 /// key
 fn is_flag(code: LinuxEventCode) -> bool {
@@ -37,59 +71,563 @@ fn is_flag(code: LinuxEventCode) -> bool {
 }
 
 
-impl Into<KeyEvent> for InputEvent {
-    fn into(self) -> KeyEvent {
+impl InputEvent {
+    fn into_key_event(self, device: DeviceId) -> KeyEvent {
         KeyEvent {
             code: self.code,
-            etype: match is_flag(self.code) {
-                true => EventType::FlagsChanged,
-                false => match self.value {
-                    0 => EventType::KeyUp,
-                    _ => EventType::KeyDown,
-                },
+            key: Key::from_evdev(self.code),
+            device: device,
+            time: Duration::new(self.time.__kernel_time_t,
+                                self.time.__kernel_suseconds_t as u32 * 1000),
+            etype: match (is_flag(self.code), self.value) {
+                (_, 2) => EventType::KeyRepeat,
+                (true, _) => EventType::FlagsChanged,
+                (false, 0) => EventType::KeyUp,
+                (false, _) => EventType::KeyDown,
             },
         }
     }
 }
 
 
-/// Reads event struct from input device
+/// Current time on the clock evdev timestamps events with
+/// (CLOCK_REALTIME)
+pub fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0))
+}
+
+
+/// Reads event structs from input device until a key event is found
 fn read_event(device: &mut File) -> Result<InputEvent, io::Error> {
-    let mut event: InputEvent = unsafe { mem::zeroed() };
     let event_size = mem::size_of::<InputEvent>();
 
-    unsafe {
-        try!(device.read_exact(slice::from_raw_parts_mut(
-            &mut event as *mut _ as *mut u8,
-            event_size
-        )));
+    loop {
+        let mut event: InputEvent = unsafe { mem::zeroed() };
+
+        unsafe {
+            try!(device.read_exact(slice::from_raw_parts_mut(
+                &mut event as *mut _ as *mut u8,
+                event_size
+            )));
+        }
+
+        if event.etype == EV_KEY as LinuxEventType {
+            debug!("read input event {:?}", event);
+            return Ok(event)
+        }
+    }
+}
+
+
+/// Builds an evdev read ioctl request, i.e. `_IOR('E', nr, [u8; len])`
+fn eviocg(nr: u32, len: usize) -> libc::c_ulong {
+    ((2 << 30) | ((len as u32) << 16) | ((b'E' as u32) << 8) | nr) as libc::c_ulong
+}
+
+/// Reads one of the evdev bitmask/string properties of a device into
+/// `buf`
+fn read_property(device: &File, nr: u32, buf: &mut [u8]) -> Result<(), io::Error> {
+    let ret = unsafe {
+        libc::ioctl(device.as_raw_fd(), eviocg(nr, buf.len()), buf.as_mut_ptr())
+    };
+    match ret {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+fn test_bit(bits: &[u8], bit: usize) -> bool {
+    bits[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+/// Revokes access to a device (EVIOCREVOKE), which wakes up a read
+/// blocked on it with an error
+fn revoke(device: &File) -> Result<(), io::Error> {
+    let request = ((1 << 30) | (4 << 16) | ((b'E' as u32) << 8) | 0x91) as libc::c_ulong;
+    match unsafe { libc::ioctl(device.as_raw_fd(), request, 0) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the name the device reports to the kernel (EVIOCGNAME)
+fn device_name(device: &File) -> String {
+    let mut buf = [0u8; 256];
+    match read_property(device, 0x06, &mut buf) {
+        Ok(_) => {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        },
+        Err(_) => "unknown".to_owned(),
+    }
+}
+
+/// Checks the device capabilities (EVIOCGBIT) and returns the reason
+/// the device is not a keyboard, if it isn't one
+fn check_keyboard(device: &File) -> Result<(), String> {
+    let mut ev_bits = [0u8; 4];
+    try!(read_property(device, 0x20, &mut ev_bits)
+         .map_err(|err| format!("unable to query event types: {}", err)));
+    if !test_bit(&ev_bits, EV_KEY as usize) {
+        return Err("does not report EV_KEY".to_owned());
+    }
+
+    let mut key_bits = [0u8; KEY_MAX / 8 + 1];
+    try!(read_property(device, 0x20 + EV_KEY, &mut key_bits)
+         .map_err(|err| format!("unable to query key codes: {}", err)));
+    match LETTER_KEYS.iter().all(|&code| test_bit(&key_bits, code as usize)) {
+        true => Ok(()),
+        false => Err("reports EV_KEY but has no letter keys".to_owned()),
+    }
+}
+
+/// Returns the event device number of paths like `/dev/input/event3`
+fn event_number(path: &Path) -> Option<u32> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| match name.starts_with("event") {
+            true => name[5..].parse().ok(),
+            false => None,
+        })
+}
+
+/// Lists /dev/input/event* in device order
+fn event_devices() -> Result<Vec<PathBuf>, io::Error> {
+    let mut paths: Vec<PathBuf> = try!(read_dir(INPUT_DIR))
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| event_number(path).is_some())
+        .collect();
+    paths.sort_by_key(|path| event_number(path));
+    Ok(paths)
+}
+
+
+/// Sends KeyEvents from a single device to the channel. Returns when
+/// the channel is closed or with the error that stopped the device,
+/// e.g. when it was unplugged or the machine was suspended.
+fn listen_device(device: &mut File, attachment: &mut Attachment) -> Result<(), io::Error> {
+    loop {
+        let event = try!(read_event(device));
+        if !attachment.send(event) {
+            return Ok(())
+        }
+    }
+}
+
+
+/// Reopens a device after a read error, doubling the delay between
+/// attempts. Gives up when the device node is gone (hotplug will
+/// attach it again if it comes back) or after MAX_RETRIES attempts.
+fn reopen(path: &Path, delay: &mut Duration) -> Option<File> {
+    for attempt in 1..MAX_RETRIES + 1 {
+        if !path.exists() {
+            return None
+        }
+        thread::sleep(*delay);
+        *delay = cmp::min(*delay * 2, Duration::from_millis(MAX_RETRY_DELAY_MS));
+
+        match File::open(path) {
+            Ok(device) => {
+                info!("Reopened {:?} after {} attempt(s)", path, attempt);
+                return Some(device)
+            },
+            Err(err) => warn!("Unable to reopen {:?} (attempt {}): {}", path, attempt, err),
+        }
+    }
+    None
+}
+
+
+/// The devices with a running listener by path, with their id and a
+/// handle to revoke them with
+type Attached = Arc<Mutex<HashMap<PathBuf, (DeviceId, File)>>>;
+
+
+/// A device being listened to. Releases the keys held on it and frees
+/// its id when its listener thread stops, including when it panics
+struct Attachment {
+    path: PathBuf,
+    id: DeviceId,
+    /// Codes of the keys held on the device
+    held: HashSet<LinuxEventCode>,
+    channel: Sender<KeyEvent>,
+    attached: Attached,
+}
+
+impl Attachment {
+    /// Sends an event of the device to the channel, returns false when
+    /// the channel is closed
+    fn send(&mut self, event: InputEvent) -> bool {
+        match event.value {
+            0 => self.held.remove(&event.code),
+            _ => self.held.insert(event.code),
+        };
+        self.channel.send(event.into_key_event(self.id)).is_ok()
+    }
+
+    /// Sends a release for every key held on the device, for when the
+    /// device is lost and its releases won't come
+    fn release_held(&mut self) {
+        let time = now();
+        for code in self.held.drain() {
+            debug!("Releasing key {} held on device {}", code, self.id);
+            let _ = self.channel.send(KeyEvent {
+                etype: match is_flag(code) {
+                    true => EventType::FlagsChanged,
+                    false => EventType::KeyUp,
+                },
+                code: code,
+                key: Key::from_evdev(code),
+                device: self.id,
+                time: time,
+            });
+        }
+    }
+
+    /// Keeps a handle to `device`, a reopened device, for `detach` to
+    /// revoke instead of the handle of the lost one
+    fn set_device(&self, device: &File) -> io::Result<()> {
+        let handle = try!(device.try_clone());
+        if let Some(entry) = self.attached.lock().unwrap().get_mut(&self.path) {
+            entry.1 = handle;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        self.release_held();
+        if let Ok(mut attached) = self.attached.lock() {
+            attached.remove(&self.path);
+        }
+    }
+}
+
+
+/// The lowest id no attached device has, ids are reused so they don't
+/// run out however often devices are plugged in
+fn free_id(attached: &HashMap<PathBuf, (DeviceId, File)>) -> Option<DeviceId> {
+    (0..DeviceId::max_value()).find(|&id| attached.values().all(|&(used, _)| used != id))
+}
+
+
+/// Tracks the devices being listened to and attaches new ones as
+/// they appear
+struct DeviceManager {
+    channel: Sender<KeyEvent>,
+    attached: Attached,
+    /// Paths from MODELM_INPUT_DEVICE, if set
+    configured: Option<Vec<PathBuf>>,
+    listeners: Vec<thread::JoinHandle<()>>,
+}
+
+impl DeviceManager {
+    fn new(channel: Sender<KeyEvent>) -> DeviceManager {
+        let configured = env::var("MODELM_INPUT_DEVICE").ok().map(|paths| {
+            info!("Using {} from MODELM_INPUT_DEVICE", paths);
+            env::split_paths(&paths).collect()
+        });
+
+        DeviceManager {
+            channel: channel,
+            attached: Arc::new(Mutex::new(HashMap::new())),
+            configured: configured,
+            listeners: vec![],
+        }
+    }
+
+    /// The devices to try attaching at startup
+    fn initial_paths(&self) -> Result<Vec<PathBuf>, io::Error> {
+        match self.configured {
+            Some(ref paths) => Ok(paths.clone()),
+            None => event_devices(),
+        }
+    }
+
+    fn is_attached(&self, path: &Path) -> bool {
+        self.attached.lock().unwrap().contains_key(path)
+    }
+
+    fn attached_count(&self) -> usize {
+        self.attached.lock().unwrap().len()
     }
 
-    if  event.etype != 1 {
-        return read_event(device);
+    /// Waits for the attached devices to stop, for when hotplug isn't
+    /// available
+    fn join(self) -> Result<(), KeyboardError> {
+        for listener in self.listeners {
+            let _ = listener.join();
+        }
+        Err(KeyboardError::Listener("all keyboard devices were detached".into()))
+    }
+
+    /// Starts listening to the device at `path` if it is a keyboard
+    /// (or was configured explicitly) and isn't attached already
+    fn attach(&mut self, path: &Path) {
+        if self.is_attached(path) {
+            return
+        }
+        if let Some(ref paths) = self.configured {
+            if !paths.iter().any(|configured| configured == path) {
+                return
+            }
+        }
+
+        let device = match File::open(path) {
+            Ok(device) => device,
+            Err(err) => return debug!("Skipping {:?}: unable to open, {}", path, err),
+        };
+        let name = device_name(&device);
+        if self.configured.is_none() {
+            match check_keyboard(&device) {
+                Ok(_) => info!("Found keyboard {:?} ({}): reports EV_KEY with letter keys", path, name),
+                Err(reason) => return debug!("Skipping {:?} ({}): {}", path, name, reason),
+            }
+        }
+
+        let handle = match device.try_clone() {
+            Ok(handle) => handle,
+            Err(err) => return warn!("Skipping {:?} ({}): {}", path, name, err),
+        };
+        let id = {
+            let mut attached = self.attached.lock().unwrap();
+            let id = match free_id(&attached) {
+                Some(id) => id,
+                None => return warn!("Skipping {:?} ({}): too many devices", path, name),
+            };
+            attached.insert(path.to_owned(), (id, handle));
+            id
+        };
+        info!("Listening to {:?} as device {}", path, id);
+
+        let attachment = Attachment {
+            path: path.to_owned(),
+            id: id,
+            held: HashSet::new(),
+            channel: self.channel.clone(),
+            attached: self.attached.clone(),
+        };
+        let handle = thread::spawn(move || {
+            let mut attachment = attachment;
+            let path = attachment.path.clone();
+            let mut device = device;
+            let mut delay = Duration::from_millis(RETRY_DELAY_MS);
+            loop {
+                let started = Instant::now();
+                match listen_device(&mut device, &mut attachment) {
+                    Ok(_) => return debug!("Stopped listening to {:?}", path),
+                    Err(err) => warn!("Lost {:?} ({}): {}", path, name, err),
+                }
+                // a reopened device starts with every key up
+                attachment.release_held();
+                // The device was working for a while, so start over
+                if started.elapsed() > Duration::from_millis(MAX_RETRY_DELAY_MS) {
+                    delay = Duration::from_millis(RETRY_DELAY_MS);
+                }
+                device = match reopen(&path, &mut delay) {
+                    Some(device) => device,
+                    None => return info!("Detached {:?} ({})", path, name),
+                };
+                if let Err(err) = attachment.set_device(&device) {
+                    warn!("Unable to detach {:?} ({}) once removed: {}", path, name, err);
+                }
+            }
+        });
+        self.listeners.push(handle);
     }
 
-    debug!("read input event {:?}", event);
-    Ok(event)
+    /// Stops listening to the device at `path` after it was removed
+    fn detach(&mut self, path: &Path) {
+        if let Some(&(id, ref device)) = self.attached.lock().unwrap().get(path) {
+            info!("{:?} was removed, detaching device {}", path, id);
+            if let Err(err) = revoke(device) {
+                warn!("Unable to detach {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+
+/// Watches /dev/input for devices being added or removed
+struct Watcher {
+    fd: libc::c_int,
 }
 
+impl Watcher {
+    fn new(dir: &str) -> Result<Watcher, io::Error> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let watcher = Watcher { fd: fd };
+
+        let path = CString::new(dir).unwrap();
+        let mask = libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_DELETE;
+        match unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } {
+            wd if wd < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(watcher),
+        }
+    }
+
+    /// Blocks until the watched directory changes. Returns the changed
+    /// event devices and the inotify mask describing the change.
+    fn changes(&mut self) -> Result<Vec<(PathBuf, u32)>, io::Error> {
+        let mut buf = [0u8; 4096];
+        let len = unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error())
+        }
 
-/// Opens the input device, will use MODELM_INPUT_DEVICE environment
-/// variable if set
-fn open_device() -> Result<File, io::Error> {
-    let path = env::var("MODELM_INPUT_DEVICE")
-        .unwrap_or("/dev/input/event0".to_owned());
-    File::open(path)
+        let header_size = mem::size_of::<libc::inotify_event>();
+        let mut changes = vec![];
+        let mut offset = 0;
+        while offset + header_size <= len as usize {
+            let event: libc::inotify_event = unsafe {
+                ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+            };
+            let name = &buf[offset + header_size..offset + header_size + event.len as usize];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            let path = Path::new(INPUT_DIR).join(OsStr::from_bytes(name));
+            if event_number(&path).is_some() {
+                changes.push((path, event.mask));
+            }
+            offset += header_size + event.len as usize;
+        }
+        Ok(changes)
+    }
 }
 
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+
+/// Sends KeyEvents from every keyboard to the channel, reading each
+/// device on its own thread. Keyboards plugged in later are attached
+/// as they appear and unplugged ones are detached. Returns only on
+/// error.
+fn start_listener(channel: &Sender<KeyEvent>) -> Result<(), KeyboardError> {
+    let mut manager = DeviceManager::new(channel.clone());
+
+    // Watch before scanning so devices added in between aren't missed
+    let watcher = Watcher::new(INPUT_DIR)
+        .map_err(|err| warn!("Unable to watch {} for new keyboards: {}", INPUT_DIR, err));
 
-/// Sends KeyEvents to the channel. Returns only on error.
-pub fn start_listener(channel: &Sender<KeyEvent>) {
-    let mut device = open_device().expect("unable to open device");
+    for path in try!(manager.initial_paths()) {
+        manager.attach(&path);
+    }
+
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(_) if manager.attached_count() > 0 => return manager.join(),
+        Err(_) => return Err(KeyboardError::Listener(format!(
+            "no keyboard devices found in {} (are you running as root?)", INPUT_DIR))),
+    };
+    if manager.attached_count() == 0 {
+        warn!("No keyboard devices found in {} (are you running as root?), \
+               waiting for one to be plugged in", INPUT_DIR);
+    }
 
     loop {
-        let _ = read_event(&mut device)
-            .map(|event| channel.send(event.into()))
-            .map_err(|err| error!("Unable to parse event, {:}", err));
+        let changes = match watcher.changes() {
+            Ok(changes) => changes,
+            Err(err) => {
+                error!("Stopped watching {} for new keyboards: {}", INPUT_DIR, err);
+                return manager.join()
+            },
+        };
+        for (path, mask) in changes {
+            match mask & libc::IN_DELETE {
+                0 => manager.attach(&path),
+                _ => manager.detach(&path),
+            }
+        }
+    }
+}
+
+
+/// Reads the keyboards in /dev/input
+pub struct EvdevSource;
+
+impl EvdevSource {
+    pub fn new() -> EvdevSource {
+        EvdevSource
+    }
+}
+
+impl InputSource for EvdevSource {
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+        info!("Running event listener...");
+        info!("Press ^C to exit.");
+        start_listener(&tx)
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{free_id, Attachment, InputEvent, TimeValue};
+    use ffi::types::EventType;
+    use std::collections::{HashMap, HashSet};
+    use std::fs::File;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn attachment_release_held_OK() -> () {
+        let (tx, rx) = channel();
+        let attached = Arc::new(Mutex::new(HashMap::new()));
+        let path = PathBuf::from("/dev/input/event3");
+        attached.lock().unwrap().insert(path.clone(), (0, File::open("/dev/null").unwrap()));
+        attached.lock().unwrap().insert(PathBuf::from("/dev/input/event5"), (1, File::open("/dev/null").unwrap()));
+        assert_eq!(free_id(&attached.lock().unwrap()), Some(2));
+
+        let mut attachment = Attachment {
+            path: path, id: 0, held: HashSet::new(), channel: tx, attached: attached.clone()
+        };
+        let event = |code, value| InputEvent {
+            time: TimeValue { __kernel_time_t: 0, __kernel_suseconds_t: 0 }, etype: 1, code: code, value: value
+        };
+        attachment.send(event(30, 1));
+        attachment.send(event(42, 1));
+        attachment.send(event(31, 1));
+        attachment.send(event(31, 0));
+        drop(attachment);
+
+        let mut released: Vec<_> = rx.iter().skip(4).map(|event| (event.etype, event.code)).collect();
+        released.sort_by_key(|&(_, code)| code);
+        assert_eq!(released, vec![(EventType::KeyUp, 30), (EventType::FlagsChanged, 42)]);
+        assert_eq!(free_id(&attached.lock().unwrap()), Some(0));
+    }
+
+    #[test]
+    fn attachment_set_device_OK() -> () {
+        let (tx, _rx) = channel();
+        let attached = Arc::new(Mutex::new(HashMap::new()));
+        let path = PathBuf::from("/dev/input/event3");
+        attached.lock().unwrap().insert(path.clone(), (0, File::open("/dev/null").unwrap()));
+
+        let attachment = Attachment {
+            path: path.clone(), id: 0, held: HashSet::new(), channel: tx, attached: attached.clone()
+        };
+        attachment.set_device(&File::open("/dev/zero").unwrap()).unwrap();
+
+        let rdev = |file: &File| file.metadata().unwrap().rdev();
+        let zero = File::open("/dev/zero").unwrap();
+        assert_eq!(rdev(&attached.lock().unwrap()[&path].1), rdev(&zero));
     }
 }
@@ -2,8 +2,7 @@
 
 pub mod types;
 
-use self::types::KeyEvent;
-use std::sync::mpsc::Sender;
+use std::time::Duration;
 
 
 // ======================================================================
@@ -16,21 +15,15 @@ use std::sync::mpsc::Sender;
 // ======================================================================
 // Compile against os ffi module
 
-/// Register a listener.
-///    - On Linux, this is a no-op because we rely on an input device
-///      that is already listening.
-///    - On OSX, this registers a Quartz Event Tap
-#[allow(unused_variables)]
-pub fn register_listener(tx: &Sender<KeyEvent>) {
-    #[cfg(target_os = "macos")] self::osx::register_listener(tx);
+/// Returns the current time on the clock the listener timestamps
+/// events with, so it can be compared with `KeyEvent::time`
+pub fn now() -> Duration {
+    #[cfg(target_os = "macos")] return self::osx::now();
+    #[cfg(target_os = "linux")] return self::linux::now();
 }
 
-/// Start listener should never return.  The listener will send events
-/// via the channel `tx`. (On OSX, `tx` should already have been
-/// passed to a registered event tap by calling `register_lisener` and
-/// will not be used here.)
-#[allow(unused_variables)]
-pub fn start_listener(tx: &Sender<KeyEvent>) {
-    #[cfg(target_os = "macos")] self::osx::start_listener();
-    #[cfg(target_os = "linux")] self::linux::start_listener(tx)
-}
+/// The keyboard of the platform as an `InputSource`:
+///    - On Linux, this reads the keyboards in /dev/input
+///    - On OSX, this registers a Quartz Event Tap
+#[cfg(target_os = "macos")] pub use self::osx::QuartzSource as NativeSource;
+#[cfg(target_os = "linux")] pub use self::linux::EvdevSource as NativeSource;
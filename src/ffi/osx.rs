@@ -5,7 +5,10 @@
 
 use libc;
 use std::sync::mpsc::Sender;
-use super::types::{EventType, KeyEvent};
+use std::time::Duration;
+use super::types::{EventType, Key, KeyEvent};
+use input::InputSource;
+use ::errors::KeyboardError;
 
 // Opaque Pointer Types
 pub type Pointer = *mut libc::c_void;
@@ -20,6 +23,7 @@ pub type CGEventTapOptions = u32;
 pub type CGEventTapPlacement = u32;
 pub type CGEventType = u32;
 pub type CGKeyCode = u16;
+pub type CGEventTimestamp = u64;
 
 // Callback Type
 pub type CGEventTapCallBack = extern "C"
@@ -31,6 +35,7 @@ pub const kCGEventKeyUp: CGEventType = 11;
 pub const kCGEventFlagsChanged: CGEventType = 12;
 pub const kCGSessionEventTap: CGEventTapLocation = 1;
 pub const kCGHeadInsertEventTap: CGEventTapPlacement = 0;
+pub const kCGKeyboardEventAutorepeat: CGEventField = 8;
 pub const kCGKeyboardEventKeycode: CGEventField = 9;
 pub const kCGTapDisabledByTimeout: CGEventType = 0xFFFFFFFE;
 
@@ -49,6 +54,7 @@ pub mod ext_quartz {
     use super::CGEventTapLocation;
     use super::CGEventTapOptions;
     use super::CGEventTapPlacement;
+    use super::CGEventTimestamp;
     use super::CGKeyCode;
 
     // Link to ApplicationServices/ApplicationServices.h and Carbon/Carbon.h
@@ -74,6 +80,10 @@ pub mod ext_quartz {
             field: CGEventField,
         ) -> CGKeyCode;
 
+        /// Get the time the event occurred in mach absolute time ticks
+        /// since system startup, see `ticks_to_duration`
+        pub fn CGEventGetTimestamp(event: CGEventRef) -> CGEventTimestamp;
+
         /// Create an event tap
         ///
         /// # Arguments
@@ -156,14 +166,18 @@ pub extern fn callback(proxy: Pointer, etype: CGEventMask, event: CGEventRef, ch
                        -> CGEventRef {
     unsafe {
         let keyCode = ext_quartz::CGEventGetIntegerValueField(event, kCGKeyboardEventKeycode);
+        let autorepeat = ext_quartz::CGEventGetIntegerValueField(event, kCGKeyboardEventAutorepeat);
         let event = KeyEvent {
             etype: match etype as u32 {
+                kCGEventKeyDown if autorepeat != 0 => EventType::KeyRepeat,
                 kCGEventKeyDown => EventType::KeyDown,
                 kCGEventKeyUp => EventType::KeyUp,
                 kCGEventFlagsChanged => EventType::FlagsChanged,
                 kCGTapDisabledByTimeout => {
                     warn!("Quartz event tap disabled because of timeout; attempting to reregister.");
-                    register_listener(channel);
+                    if let Err(err) = register_listener(channel) {
+                        error!("Unable to reregister event tap: {:?}", err);
+                    }
                     return event;
                 },
                 _ => {
@@ -172,6 +186,9 @@ pub extern fn callback(proxy: Pointer, etype: CGEventMask, event: CGEventRef, ch
                 },
             },
             code: keyCode,
+            key: Key::from_macos(keyCode),
+            device: 0,
+            time: ticks_to_duration(ext_quartz::CGEventGetTimestamp(event)),
         };
         debug!("Received event: {:?}", event);
         let _ = channel.send(event);
@@ -179,20 +196,39 @@ pub extern fn callback(proxy: Pointer, etype: CGEventMask, event: CGEventRef, ch
     event
 }
 
+/// Converts mach absolute time, which event timestamps are measured in,
+/// to a Duration. Ticks are nanoseconds on Intel but not on Apple
+/// Silicon, so they go through the timebase.
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = unsafe {
+        let mut timebase: libc::mach_timebase_info = ::std::mem::zeroed();
+        libc::mach_timebase_info(&mut timebase);
+        ticks * timebase.numer as u64 / timebase.denom as u64
+    };
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// Current time on the clock Quartz timestamps events with (time since
+/// system startup)
+pub fn now() -> Duration {
+    ticks_to_duration(unsafe { libc::mach_absolute_time() })
+}
+
 /// Redefine macro for bitshifting from header as function here
 pub fn CGEventMaskBit(eventType: u32) -> CGEventMask {
     1 << (eventType)
 }
 
 /// Safe wrapper around CFRunLoopRun
-pub fn start_listener() {
+fn start_listener() -> Result<(), KeyboardError> {
     unsafe {
         ext_quartz::CFRunLoopRun();
     }
+    Err(KeyboardError::Listener("Quartz run loop exited".into()))
 }
 
 /// Registeres an event tap
-pub fn register_listener(tx: &Sender<KeyEvent>) {
+fn register_listener(tx: &Sender<KeyEvent>) -> Result<(), KeyboardError> {
     let mask = CGEventMaskBit(kCGEventKeyDown)
         | CGEventMaskBit(kCGEventKeyUp)
         | CGEventMaskBit(kCGEventFlagsChanged);
@@ -209,8 +245,10 @@ pub fn register_listener(tx: &Sender<KeyEvent>) {
             callback,
             tx,
         );
-        assert!(!event_tap.is_null(),
-                "Unable to create event tap. Please make sure you have the correct permissions");
+        if event_tap.is_null() {
+            return Err(KeyboardError::Listener(
+                "Unable to create event tap. Please make sure you have the correct permissions".into()));
+        }
         info!("Created event tap...");
 
         let allocator = ext_quartz::kCFAllocatorDefault;
@@ -227,4 +265,29 @@ pub fn register_listener(tx: &Sender<KeyEvent>) {
         ext_quartz::CGEventTapEnable(event_tap, true);
 
     }
+    Ok(())
+}
+
+/// Listens to a Quartz Event Tap
+pub struct QuartzSource;
+
+impl QuartzSource {
+    pub fn new() -> QuartzSource {
+        QuartzSource
+    }
+}
+
+impl InputSource for QuartzSource {
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+        // The event tap keeps a reference to `tx`, which lives for as
+        // long as the run loop
+        try!(register_listener(&tx));
+        info!("Running event listener...");
+        info!("Press ^C to exit.");
+        start_listener()
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
 }
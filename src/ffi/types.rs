@@ -1,12 +1,20 @@
 /// Define types to be passed between os and modelm
 
+use std::str::FromStr;
+use std::time::Duration;
+use ::errors::KeyboardError;
+
 pub type KeyCode = u16;
 
-#[derive(Debug)]
+/// Identifies the input device an event came from
+pub type DeviceId = u16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub enum EventType {
     KeyDown,
     KeyUp,
+    KeyRepeat,
     FlagsChanged,
 }
 
@@ -15,4 +23,369 @@ pub enum EventType {
 pub struct KeyEvent {
     pub etype: EventType,
     pub code: KeyCode,
+    /// The logical key for `code`
+    pub key: Key,
+    pub device: DeviceId,
+    /// When the event happened, measured on the clock of `ffi::now`
+    pub time: Duration,
+}
+
+
+/// Logical keys, named after their position on a US layout so the same
+/// key has the same name on every platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15,
+    F16, F17, F18, F19, Grave, Digit1, Digit2, Digit3, Digit4, Digit5, Digit6,
+    Digit7, Digit8, Digit9, Digit0, Minus, Equal, IntlYen, Backspace, Tab, Q,
+    W, E, R, T, Y, U, I, O, P, LeftBracket, RightBracket, Backslash, CapsLock,
+    A, S, D, F, G, H, J, K, L, Semicolon, Apostrophe, Enter, LeftShift,
+    IntlBackslash, Z, X, C, V, B, N, M, Comma, Period, Slash, IntlRo,
+    RightShift, LeftCtrl, LeftMeta, LeftAlt, Muhenkan, Lang2, Space, Henkan,
+    Lang1, KanaMode, RightAlt, RightMeta, Menu, RightCtrl, Fn, PrintScreen,
+    ScrollLock, Pause, Insert, Home, PageUp, Delete, End, PageDown, Up, Left,
+    Down, Right, NumLock, KeypadDivide, KeypadMultiply, KeypadMinus, Keypad7,
+    Keypad8, Keypad9, KeypadPlus, Keypad4, Keypad5, Keypad6, Keypad1, Keypad2,
+    Keypad3, KeypadEnter, Keypad0, KeypadDecimal, KeypadEqual, KeypadComma,
+    Unknown,
+}
+
+/// Marks keys that a platform doesn't have
+const NONE: KeyCode = 0xffff;
+
+/// Every key with its name, Linux evdev code and macOS CGKeyCode
+static KEYS: &'static [(Key, &'static str, KeyCode, KeyCode)] = &[
+    (Key::Escape, "Escape", 1, 53),
+    (Key::F1, "F1", 59, 122),
+    (Key::F2, "F2", 60, 120),
+    (Key::F3, "F3", 61, 99),
+    (Key::F4, "F4", 62, 118),
+    (Key::F5, "F5", 63, 96),
+    (Key::F6, "F6", 64, 97),
+    (Key::F7, "F7", 65, 98),
+    (Key::F8, "F8", 66, 100),
+    (Key::F9, "F9", 67, 101),
+    (Key::F10, "F10", 68, 109),
+    (Key::F11, "F11", 87, 103),
+    (Key::F12, "F12", 88, 111),
+    (Key::F13, "F13", 183, 105),
+    (Key::F14, "F14", 184, 107),
+    (Key::F15, "F15", 185, 113),
+    (Key::F16, "F16", 186, 106),
+    (Key::F17, "F17", 187, 64),
+    (Key::F18, "F18", 188, 79),
+    (Key::F19, "F19", 189, 80),
+    (Key::Grave, "Grave", 41, 50),
+    (Key::Digit1, "Digit1", 2, 18),
+    (Key::Digit2, "Digit2", 3, 19),
+    (Key::Digit3, "Digit3", 4, 20),
+    (Key::Digit4, "Digit4", 5, 21),
+    (Key::Digit5, "Digit5", 6, 23),
+    (Key::Digit6, "Digit6", 7, 22),
+    (Key::Digit7, "Digit7", 8, 26),
+    (Key::Digit8, "Digit8", 9, 28),
+    (Key::Digit9, "Digit9", 10, 25),
+    (Key::Digit0, "Digit0", 11, 29),
+    (Key::Minus, "Minus", 12, 27),
+    (Key::Equal, "Equal", 13, 24),
+    (Key::IntlYen, "IntlYen", 124, 93),
+    (Key::Backspace, "Backspace", 14, 51),
+    (Key::Tab, "Tab", 15, 48),
+    (Key::Q, "Q", 16, 12),
+    (Key::W, "W", 17, 13),
+    (Key::E, "E", 18, 14),
+    (Key::R, "R", 19, 15),
+    (Key::T, "T", 20, 17),
+    (Key::Y, "Y", 21, 16),
+    (Key::U, "U", 22, 32),
+    (Key::I, "I", 23, 34),
+    (Key::O, "O", 24, 31),
+    (Key::P, "P", 25, 35),
+    (Key::LeftBracket, "LeftBracket", 26, 33),
+    (Key::RightBracket, "RightBracket", 27, 30),
+    (Key::Backslash, "Backslash", 43, 42),
+    (Key::CapsLock, "CapsLock", 58, 57),
+    (Key::A, "A", 30, 0),
+    (Key::S, "S", 31, 1),
+    (Key::D, "D", 32, 2),
+    (Key::F, "F", 33, 3),
+    (Key::G, "G", 34, 5),
+    (Key::H, "H", 35, 4),
+    (Key::J, "J", 36, 38),
+    (Key::K, "K", 37, 40),
+    (Key::L, "L", 38, 37),
+    (Key::Semicolon, "Semicolon", 39, 41),
+    (Key::Apostrophe, "Apostrophe", 40, 39),
+    (Key::Enter, "Enter", 28, 36),
+    (Key::LeftShift, "LeftShift", 42, 56),
+    (Key::IntlBackslash, "IntlBackslash", 86, 10),
+    (Key::Z, "Z", 44, 6),
+    (Key::X, "X", 45, 7),
+    (Key::C, "C", 46, 8),
+    (Key::V, "V", 47, 9),
+    (Key::B, "B", 48, 11),
+    (Key::N, "N", 49, 45),
+    (Key::M, "M", 50, 46),
+    (Key::Comma, "Comma", 51, 43),
+    (Key::Period, "Period", 52, 47),
+    (Key::Slash, "Slash", 53, 44),
+    (Key::IntlRo, "IntlRo", 89, 94),
+    (Key::RightShift, "RightShift", 54, 60),
+    (Key::LeftCtrl, "LeftCtrl", 29, 59),
+    (Key::LeftMeta, "LeftMeta", 125, 55),
+    (Key::LeftAlt, "LeftAlt", 56, 58),
+    (Key::Muhenkan, "Muhenkan", 94, NONE),
+    (Key::Lang2, "Lang2", 123, 102),
+    (Key::Space, "Space", 57, 49),
+    (Key::Henkan, "Henkan", 92, NONE),
+    (Key::Lang1, "Lang1", 122, 104),
+    (Key::KanaMode, "KanaMode", 93, NONE),
+    (Key::RightAlt, "RightAlt", 100, 61),
+    (Key::RightMeta, "RightMeta", 126, 54),
+    (Key::Menu, "Menu", 127, 110),
+    (Key::RightCtrl, "RightCtrl", 97, 62),
+    (Key::Fn, "Fn", 464, 63),
+    (Key::PrintScreen, "PrintScreen", 99, NONE),
+    (Key::ScrollLock, "ScrollLock", 70, NONE),
+    (Key::Pause, "Pause", 119, NONE),
+    (Key::Insert, "Insert", 110, 114),
+    (Key::Home, "Home", 102, 115),
+    (Key::PageUp, "PageUp", 104, 116),
+    (Key::Delete, "Delete", 111, 117),
+    (Key::End, "End", 107, 119),
+    (Key::PageDown, "PageDown", 109, 121),
+    (Key::Up, "Up", 103, 126),
+    (Key::Left, "Left", 105, 123),
+    (Key::Down, "Down", 108, 125),
+    (Key::Right, "Right", 106, 124),
+    (Key::NumLock, "NumLock", 69, 71),
+    (Key::KeypadDivide, "KeypadDivide", 98, 75),
+    (Key::KeypadMultiply, "KeypadMultiply", 55, 67),
+    (Key::KeypadMinus, "KeypadMinus", 74, 78),
+    (Key::Keypad7, "Keypad7", 71, 89),
+    (Key::Keypad8, "Keypad8", 72, 91),
+    (Key::Keypad9, "Keypad9", 73, 92),
+    (Key::KeypadPlus, "KeypadPlus", 78, 69),
+    (Key::Keypad4, "Keypad4", 75, 86),
+    (Key::Keypad5, "Keypad5", 76, 87),
+    (Key::Keypad6, "Keypad6", 77, 88),
+    (Key::Keypad1, "Keypad1", 79, 83),
+    (Key::Keypad2, "Keypad2", 80, 84),
+    (Key::Keypad3, "Keypad3", 81, 85),
+    (Key::KeypadEnter, "KeypadEnter", 96, 76),
+    (Key::Keypad0, "Keypad0", 82, 82),
+    (Key::KeypadDecimal, "KeypadDecimal", 83, 65),
+    (Key::KeypadEqual, "KeypadEqual", 117, 81),
+    (Key::KeypadComma, "KeypadComma", 121, 95),
+];
+
+impl Key {
+    /// Returns the key for a Linux evdev key code
+    pub fn from_evdev(code: KeyCode) -> Key {
+        KEYS.iter().find(|k| k.2 == code).map_or(Key::Unknown, |k| k.0)
+    }
+
+    /// Returns the key for a macOS virtual key code (CGKeyCode)
+    pub fn from_macos(code: KeyCode) -> Key {
+        match code {
+            NONE => Key::Unknown,
+            code => KEYS.iter().find(|k| k.3 == code).map_or(Key::Unknown, |k| k.0),
+        }
+    }
+
+    /// Returns the key for a key code of this platform
+    pub fn from_native(code: KeyCode) -> Key {
+        #[cfg(target_os = "macos")] return Key::from_macos(code);
+        #[cfg(target_os = "linux")] return Key::from_evdev(code);
+    }
+
+    fn entry(&self) -> Option<&'static (Key, &'static str, KeyCode, KeyCode)> {
+        KEYS.iter().find(|k| k.0 == *self)
+    }
+
+    /// Returns the Linux evdev key code of the key
+    pub fn evdev_code(&self) -> Option<KeyCode> {
+        self.entry().map(|k| k.2)
+    }
+
+    /// Returns the macOS virtual key code of the key
+    pub fn macos_code(&self) -> Option<KeyCode> {
+        self.entry().map(|k| k.3).and_then(|code| match code {
+            NONE => None,
+            code => Some(code),
+        })
+    }
+
+    /// Returns the key code of the key on this platform
+    pub fn native_code(&self) -> Option<KeyCode> {
+        #[cfg(target_os = "macos")] return self.macos_code();
+        #[cfg(target_os = "linux")] return self.evdev_code();
+    }
+
+    /// Returns the name of the key, e.g. `Enter` or `Digit1`
+    pub fn name(&self) -> &'static str {
+        self.entry().map_or("Unknown", |k| k.1)
+    }
+
+    /// Returns the key that types `c` on a US layout and whether shift
+    /// has to be held for it
+    pub fn from_char(c: char) -> Option<(Key, bool)> {
+        let key = match c.to_ascii_lowercase() {
+            'a' => Key::A, 'b' => Key::B, 'c' => Key::C, 'd' => Key::D, 'e' => Key::E,
+            'f' => Key::F, 'g' => Key::G, 'h' => Key::H, 'i' => Key::I, 'j' => Key::J,
+            'k' => Key::K, 'l' => Key::L, 'm' => Key::M, 'n' => Key::N, 'o' => Key::O,
+            'p' => Key::P, 'q' => Key::Q, 'r' => Key::R, 's' => Key::S, 't' => Key::T,
+            'u' => Key::U, 'v' => Key::V, 'w' => Key::W, 'x' => Key::X, 'y' => Key::Y,
+            'z' => Key::Z,
+            '1' | '!' => Key::Digit1, '2' | '@' => Key::Digit2, '3' | '#' => Key::Digit3,
+            '4' | '$' => Key::Digit4, '5' | '%' => Key::Digit5, '6' | '^' => Key::Digit6,
+            '7' | '&' => Key::Digit7, '8' | '*' => Key::Digit8, '9' | '(' => Key::Digit9,
+            '0' | ')' => Key::Digit0,
+            '-' | '_' => Key::Minus, '=' | '+' => Key::Equal,
+            '[' | '{' => Key::LeftBracket, ']' | '}' => Key::RightBracket,
+            '\\' | '|' => Key::Backslash, ';' | ':' => Key::Semicolon,
+            '\'' | '"' => Key::Apostrophe, '`' | '~' => Key::Grave,
+            ',' | '<' => Key::Comma, '.' | '>' => Key::Period, '/' | '?' => Key::Slash,
+            ' ' => Key::Space, '\n' | '\r' => Key::Enter, '\t' => Key::Tab,
+            '\x08' | '\x7f' => Key::Backspace, '\x1b' => Key::Escape,
+            _ => return None,
+        };
+        let shift = c.is_ascii_uppercase() || "!@#$%^&*()_+{}|:\"~<>?".contains(c);
+        Some((key, shift))
+    }
+}
+
+impl FromStr for Key {
+    type Err = KeyboardError;
+
+    /// Parses a key name, ignoring case
+    fn from_str(name: &str) -> Result<Key, KeyboardError> {
+        KEYS.iter()
+            .find(|k| k.1.eq_ignore_ascii_case(name))
+            .map(|k| k.0)
+            .ok_or(KeyboardError::Config(format!("Unknown key name: {}", name)))
+    }
+}
+
+
+/// Named groups of keys a switch can match
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyGroup {
+    /// The letters A to Z
+    Alpha,
+    /// The digits on top of the letters
+    Digits,
+    /// Shift, Ctrl, Alt, Meta, Fn and CapsLock
+    Modifiers,
+    /// F1 to F19
+    Function,
+    /// The arrows and the Insert, Delete, Home, End, PageUp, PageDown block
+    Navigation,
+    /// The keypad and NumLock
+    Numpad,
+}
+
+impl KeyGroup {
+    /// Returns true if `key` is in the group
+    pub fn contains(&self, key: Key) -> bool {
+        use self::Key::*;
+        match *self {
+            KeyGroup::Alpha => match key {
+                A | B | C | D | E | F | G | H | I | J | K | L | M | N | O | P | Q | R
+                    | S | T | U | V | W | X | Y | Z => true,
+                _ => false,
+            },
+            KeyGroup::Digits => match key {
+                Digit1 | Digit2 | Digit3 | Digit4 | Digit5 | Digit6 | Digit7 | Digit8
+                    | Digit9 | Digit0 => true,
+                _ => false,
+            },
+            KeyGroup::Modifiers => match key {
+                LeftShift | RightShift | LeftCtrl | RightCtrl | LeftAlt | RightAlt
+                    | LeftMeta | RightMeta | Fn | CapsLock => true,
+                _ => false,
+            },
+            KeyGroup::Function => match key {
+                F1 | F2 | F3 | F4 | F5 | F6 | F7 | F8 | F9 | F10 | F11 | F12 | F13
+                    | F14 | F15 | F16 | F17 | F18 | F19 => true,
+                _ => false,
+            },
+            KeyGroup::Navigation => match key {
+                Up | Down | Left | Right | Insert | Delete | Home | End | PageUp
+                    | PageDown => true,
+                _ => false,
+            },
+            KeyGroup::Numpad => match key {
+                NumLock | KeypadDivide | KeypadMultiply | KeypadMinus | KeypadPlus
+                    | KeypadEnter | KeypadDecimal | KeypadEqual | KeypadComma | Keypad0
+                    | Keypad1 | Keypad2 | Keypad3 | Keypad4 | Keypad5 | Keypad6
+                    | Keypad7 | Keypad8 | Keypad9 => true,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl FromStr for KeyGroup {
+    type Err = KeyboardError;
+
+    fn from_str(name: &str) -> Result<KeyGroup, KeyboardError> {
+        match &*name.to_ascii_lowercase() {
+            "alpha" => Ok(KeyGroup::Alpha),
+            "digits" => Ok(KeyGroup::Digits),
+            "modifiers" => Ok(KeyGroup::Modifiers),
+            "function" => Ok(KeyGroup::Function),
+            "navigation" => Ok(KeyGroup::Navigation),
+            "numpad" => Ok(KeyGroup::Numpad),
+            _ => Err(KeyboardError::Config(format!(
+                "group must be one of alpha, digits, modifiers, function, navigation \
+                 or numpad, not {}", name))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{Key, KeyGroup};
+
+    #[test]
+    fn key_platform_codes_OK() -> () {
+        assert_eq!(Key::from_evdev(28), Key::Enter);
+        assert_eq!(Key::from_macos(36), Key::Enter);
+        assert_eq!(Key::from_evdev(57), Key::Space);
+        assert_eq!(Key::from_macos(49), Key::Space);
+        assert_eq!(Key::Enter.evdev_code(), Some(28));
+        assert_eq!(Key::Henkan.macos_code(), None);
+        assert_eq!(Key::from_macos(0xffff), Key::Unknown);
+    }
+
+    #[test]
+    fn key_names_OK() -> () {
+        assert_eq!("KeypadEnter".parse::<Key>().unwrap(), Key::KeypadEnter);
+        assert_eq!("space".parse::<Key>().unwrap(), Key::Space);
+        assert_eq!(Key::Digit1.name(), "Digit1");
+        assert!("Return".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn key_from_char_OK() -> () {
+        assert_eq!(Key::from_char('a'), Some((Key::A, false)));
+        assert_eq!(Key::from_char('A'), Some((Key::A, true)));
+        assert_eq!(Key::from_char('?'), Some((Key::Slash, true)));
+        assert_eq!(Key::from_char('\n'), Some((Key::Enter, false)));
+        assert_eq!(Key::from_char('é'), None);
+    }
+
+    #[test]
+    fn key_groups_OK() -> () {
+        let group: KeyGroup = "navigation".parse().unwrap();
+        assert!(group.contains(Key::PageDown));
+        assert!(!group.contains(Key::Keypad3));
+        assert!(KeyGroup::Modifiers.contains(Key::RightAlt));
+        assert_eq!("Alpha".parse::<KeyGroup>().unwrap(), KeyGroup::Alpha);
+        assert!("letters".parse::<KeyGroup>().is_err());
+    }
 }
@@ -0,0 +1,101 @@
+//! Sources of keyboard events
+//!
+//! A Keyboard plays sounds for the events an `InputSource` sends it.
+//! The platform's keyboard is read by `ffi::NativeSource`, recorded
+//! sessions are played back by `session::ReplaySource`, and other
+//! producers of events can implement `InputSource` themselves.
+//!
+//! # Example
+//! ```ignore
+//! ears::init();
+//! let mut keyboard = Keyboard::new().load_config_yaml(config)?;
+//! keyboard.listen(NativeSource::new())?;
+//! ```
+
+use ffi::types::KeyEvent;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use ::errors::KeyboardError;
+
+/// Produces KeyEvents for a Keyboard
+pub trait InputSource: Send {
+    /// Sends events to `tx` until the source is exhausted or fails.
+    /// Called on a thread of its own by `Keyboard::listen`.
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError>;
+
+    /// Whether events happen as they are sent, i.e. their timestamps
+    /// are on the clock of `ffi::now`
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    /// A handle that makes `run` return from another thread. Sources
+    /// without one return once they fail to send, after the receiver
+    /// is dropped.
+    fn stop_handle(&self) -> Option<StopHandle> {
+        None
+    }
+}
+
+
+/// Asks a running source to stop
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub fn new() -> StopHandle
+    {
+        StopHandle::default()
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+
+/// Sends a fixed list of events, as fast as possible unless timed, e.g.
+/// for tests
+pub struct VecSource {
+    events: Vec<KeyEvent>,
+    timed: bool,
+}
+
+impl VecSource {
+    pub fn new(events: Vec<KeyEvent>) -> VecSource
+    {
+        VecSource { events: events, timed: false }
+    }
+
+    /// Waits between events as long as their times are apart
+    pub fn timed(mut self) -> VecSource
+    {
+        self.timed = true;
+        self
+    }
+}
+
+impl InputSource for VecSource {
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+        let mut previous: Option<Duration> = None;
+        for event in self.events.drain(..) {
+            if let (true, Some(previous)) = (self.timed, previous) {
+                thread::sleep(event.time.checked_sub(previous).unwrap_or(Duration::new(0, 0)));
+            }
+            previous = Some(event.time);
+            if tx.send(event).is_err() {
+                break
+            }
+        }
+        Ok(())
+    }
+}
@@ -3,35 +3,54 @@
 //! This module contains a Keyboard struct that emulates the sounds of
 //! your favorite keyboard.
 //!
-//! The Keyboard currently uses the `ears` crate to play sounds with
-//! OpenAL.
+//! The Keyboard plays sounds with OpenAL through the `ears` crate
+//! unless it is given another `AudioBackend`.
 //!
 //! # Example
 //! ```ignore
 //! ears::init();
-//! Keyboard::new("resources/modelm").listen();
+//! Keyboard::new("resources/modelm").listen(NativeSource::new());
 //! ```
 
 use ::DEFAULT_SOUND_FILE_REGEX;
-use ffi::{register_listener, start_listener};
-use ffi::types::{EventType, KeyCode, KeyEvent};
+use audio::{self, AudioBackend};
+use dynamics::{Dynamics, Stroke};
+use ffi::now;
+use ffi::types::{DeviceId, EventType, Key, KeyCode, KeyEvent};
+use input::InputSource;
+use layout::Layout;
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::fs::read_dir;
+use std::io::Write;
+use std::path::Path;
 use std::sync::mpsc::channel;
 use std::thread;
+use std::time::Duration;
+use session;
 use switch::Switch;
+use voice::{StealPolicy, VoicePool, DEFAULT_MAX_VOICES};
 use yaml_rust;
 use yaml_rust::Yaml;
 use ::errors::KeyboardError;
 
+/// Number of key codes resolved ahead of time, covers every evdev key
+/// code (KEY_MAX is 0x2ff) and every macOS virtual key code
+const SWITCH_TABLE_SIZE: usize = 0x300;
+
 /// Keyboard representation
 #[repr(C)]
 pub struct Keyboard {
     switches: Vec<Switch>,
+    /// The key and the index of the switch that handles it for every
+    /// key code below SWITCH_TABLE_SIZE
+    switch_table: Vec<(Key, Option<usize>)>,
     sound_file_regex: Regex,
-    keys_down: HashSet<KeyCode>,
+    /// Keys currently held, the time they were pressed and how hard
+    keys_down: HashMap<(DeviceId, KeyCode), (Duration, Stroke)>,
+    last_keydown: Option<Duration>,
     options: KeyboardOptions,
+    voices: VoicePool,
 }
 
 
@@ -39,6 +58,14 @@ pub struct KeyboardOptions {
     pub x_scale: f32,
     pub volume: f32,
     pub modifier_keys: bool,
+    /// Where the keys are, for placing clicks
+    pub layout: Layout,
+    /// Where the listener is, the keyboard lies in front of the origin
+    pub listener_position: [f32; 3],
+    /// Which way the listener faces and which way is up
+    pub listener_orientation: ([f32; 3], [f32; 3]),
+    /// How typing changes the clicks
+    pub dynamics: Dynamics,
 }
 
 
@@ -49,6 +76,10 @@ impl Default for KeyboardOptions {
             x_scale: 1.0,
             volume: 1.0,
             modifier_keys: false,
+            layout: Layout::default(),
+            listener_position: [0.0, 0.0, 0.0],
+            listener_orientation: ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+            dynamics: Dynamics::default(),
         }
     }
 }
@@ -61,9 +92,12 @@ impl Keyboard {
     pub fn new() -> Keyboard
     {
         Keyboard {
-            keys_down: HashSet::new(),
+            keys_down: HashMap::new(),
+            last_keydown: None,
             options: KeyboardOptions::default(),
+            voices: VoicePool::new(audio::default_backend()),
             switches: vec![],
+            switch_table: vec![],
             sound_file_regex: Regex::new(DEFAULT_SOUND_FILE_REGEX).unwrap(),
         }
     }
@@ -76,6 +110,16 @@ impl Keyboard {
         Keyboard { options: options, .. Keyboard::new() }
     }
 
+    /// Plays sounds through `backend` instead of the default backend
+    ///
+    /// Has to be called before any sounds are loaded, e.g.
+    /// `Keyboard::new().with_backend(backend).load_config_yaml(config)`
+    pub fn with_backend(mut self, backend: Box<dyn AudioBackend>) -> Keyboard
+    {
+        self.voices = VoicePool::new(backend);
+        self
+    }
+
     pub fn load_config_yaml(mut self, config: &str) -> Result<Keyboard, KeyboardError>
     {
         let parsed = try!(yaml_rust::YamlLoader::load_from_str(config));
@@ -85,9 +129,37 @@ impl Keyboard {
                                  "config must have Array [switches]");
 
         for switch_config in switches {
-            self.switches.push(try!(Switch::from_yaml(&switch_config)));
+            self.switches.push(try!(Switch::from_yaml(&switch_config, &mut self.voices)));
         }
 
+        if let Yaml::String(ref layout) = yaml["layout"] {
+            info!("Parsed layout: {}", layout);
+            self.options.layout = try!(Layout::load(layout, Path::new(".")));
+        }
+
+        if !yaml["polyphony"].is_badvalue() || !yaml["voice_stealing"].is_badvalue() {
+            let max_voices = match yaml["polyphony"] {
+                Yaml::Integer(max_voices) if max_voices > 0 => max_voices as usize,
+                Yaml::BadValue => DEFAULT_MAX_VOICES,
+                _ => return Err(KeyboardError::Config(
+                    "config must have a positive Integer [polyphony]".into())),
+            };
+            let steal = match yaml["voice_stealing"] {
+                Yaml::String(ref steal) => try!(steal.parse()),
+                Yaml::BadValue => StealPolicy::Oldest,
+                _ => return Err(KeyboardError::Config(
+                    "config must have String [voice_stealing]".into())),
+            };
+            info!("Parsed polyphony: {}, voice_stealing: {:?}", max_voices, steal);
+            self.voices.set_polyphony(max_voices, steal);
+        }
+
+        if !yaml["dynamics"].is_badvalue() {
+            self.options.dynamics = try!(Dynamics::from_yaml(&yaml["dynamics"]));
+            info!("Parsed dynamics: {:?}", self.options.dynamics);
+        }
+        self.build_switch_table();
+
         Ok(self)
     }
 
@@ -101,10 +173,11 @@ impl Keyboard {
         for path in read_dir(directory).unwrap() {
             let path = path.unwrap().path();
             if self.sound_file_regex.is_match(path.to_str().unwrap()) {
-                switch = try!(switch.load_sound_keydown(&path));
+                switch = try!(switch.load_sound_keydown(&path, &mut self.voices));
             }
         }
         self.switches.push(switch);
+        self.build_switch_table();
         Ok(self)
     }
 
@@ -115,6 +188,7 @@ impl Keyboard {
     pub fn switch(mut self, switch: Switch) -> Keyboard
     {
         self.switches.push(switch);
+        self.build_switch_table();
         self
     }
 
@@ -141,86 +215,173 @@ impl Keyboard {
         self
     }
 
+    /// Sets the physical layout of the keyboard.
+    pub fn set_layout(mut self, layout: Layout) -> Keyboard {
+        self.options.layout = layout;
+        self
+    }
+
     /// Listener to play sound.
     ///
     /// Play a sound when the an event is added to the channel by the
-    /// callback
+    /// input source. Returns when the source stops, with the reason it
+    /// failed if it did.
+    ///
+    /// # Argument
+    /// `source` - Where to read events from, e.g. `ffi::NativeSource`
     ///
     /// # Example
     /// ```ignore
     /// ears::init();
-    /// Keyboard::new("resources");
+    /// Keyboard::new("resources").listen(NativeSource::new());
     /// ```
-    pub fn listen(&mut self) {
+    pub fn listen<S: InputSource + 'static>(&mut self, source: S) -> Result<(), KeyboardError> {
+        self.listen_with(source, |_| Ok(()))
+    }
+
+    /// Listens like `listen` and also writes every event to a session
+    /// log that can be played back with a `session::ReplaySource`.
+    ///
+    /// # Arguments
+    /// `source` - Where to read events from
+    /// `writer` - Where to write the session log
+    pub fn record<S, W>(&mut self, source: S, mut writer: W) -> Result<(), KeyboardError>
+        where S: InputSource + 'static, W: Write
+    {
+        try!(writeln!(writer, "{}", session::HEADER));
+        self.listen_with(source, |event| session::write_event(&mut writer, event))
+    }
+
+    /// Listens for events, passing each one to `observe` before it
+    /// is handled.
+    fn listen_with<S, F>(&mut self, mut source: S, mut observe: F) -> Result<(), KeyboardError>
+        where S: InputSource + 'static, F: FnMut(&KeyEvent) -> Result<(), KeyboardError>
+    {
         let (tx, rx) = channel();
+        let live = source.is_live();
+
+        self.place_listener();
 
         // create listener thread
-        thread::spawn(move || {
-            register_listener(&tx);
-            info!("Running event listener...");
-            info!("Press ^C to exit.");
-            start_listener(&tx);
-        });
+        let listener = thread::spawn(move || source.run(tx));
 
         // poll channel for events
-        loop {
-            match rx.recv() {
-                Ok(event) => self.handle_event(event),
-                Err(err) => return info!("Channel to listener closed, {:}", err),
+        while let Ok(event) = rx.recv() {
+            if live {
+                debug!("Latency {:?}", elapsed(event.time, now()));
             }
+            try!(observe(&event));
+            self.handle_event(event);
+        }
+        info!("Channel to listener closed");
+
+        // supervise the listener thread
+        match listener.join() {
+            Ok(result) => result,
+            Err(_) => Err(KeyboardError::Listener("listener thread panicked".into())),
         }
     }
 
-    /// Returns the index of the handler for a KeyCode
+    /// Places the listener of the audio backend as set in the options,
+    /// `listen` does this before handling the first event
+    pub fn place_listener(&mut self) {
+        let (at, up) = self.options.listener_orientation;
+        self.voices.set_listener(self.options.listener_position, at, up);
+    }
+
+    /// Resolves the switch of every key code in the table, has to run
+    /// whenever the switches change
+    fn build_switch_table(&mut self) {
+        let switches = &self.switches;
+        self.switch_table = (0..SWITCH_TABLE_SIZE as KeyCode)
+            .map(|code| {
+                let key = Key::from_native(code);
+                (key, switches.iter().position(|switch| switch.handles_key(code, key)))
+            })
+            .collect();
+    }
+
+    /// Returns the index of the handler for an event's key
+    ///
+    /// Looks the key code up in the switch table, only events from
+    /// outside the table or whose key is not the native key of their
+    /// code (e.g. replayed from another platform) test every switch.
     ///
     /// # Argument
-    /// `code` - The key code  of the event
-    fn get_switch_index(&self, code: KeyCode) -> Option<usize> {
-        for (i, switch) in self.switches.iter().enumerate() {
-            if switch.handles(code) {
-                return Some(i)
-            }
+    /// `event` - The event to find a handler for
+    fn get_switch_index(&self, event: &KeyEvent) -> Option<usize> {
+        match self.switch_table.get(event.code as usize) {
+            Some(&(key, index)) if key == event.key => index,
+            _ => self.switches.iter().position(|switch| switch.handles(event)),
         }
-        None
     }
 
-    /// Looks-up the handler for a KeyCode and calls the handler with
-    /// the event.
+    /// Looks-up the handler for a key and calls the handler with the
+    /// event.
     ///
-    /// # Argument
+    /// # Arguments
     /// `event` - The instance of the event to handle
-    pub fn call_event_handler(&mut self, event: KeyEvent) {
-        match self.get_switch_index(event.code) {
-            Some(i) => self.switches[i].handle_event(event, &self.options),
+    /// `stroke` - How hard the key was hit
+    pub fn call_event_handler(&mut self, event: KeyEvent, stroke: Stroke) {
+        match self.get_switch_index(&event) {
+            Some(i) => self.switches[i].handle_event(event, stroke, &self.options, &mut self.voices),
             None => (),
         };
     }
 
+    /// Records a key as pressed at `time`, returns how hard it was hit
+    fn press(&mut self, key: (DeviceId, KeyCode), time: Duration) -> Stroke {
+        let interval = self.last_keydown.map(|last_keydown| elapsed(last_keydown, time));
+        if let Some(interval) = interval {
+            debug!("Interval since last keydown {:?}", interval);
+        }
+        let stroke = self.options.dynamics.keydown(interval);
+        self.last_keydown = Some(time);
+        self.keys_down.insert(key, (time, stroke));
+        stroke
+    }
+
+    /// Records a key as released at `time`, returns how hard it was
+    /// released
+    fn release(&mut self, key: (DeviceId, KeyCode), time: Duration) -> Option<Stroke> {
+        let (pressed, stroke) = match self.keys_down.remove(&key) {
+            Some(down) => down,
+            None => return None,
+        };
+        let held = elapsed(pressed, time);
+        debug!("Key {:?} held for {:?}", key, held);
+        Some(self.options.dynamics.keyup(stroke, held))
+    }
+
     /// Adjusts keyboard state given Event and calls a handler.
     ///
     /// # Argument
     /// `event` - The instance of the event to record and handle
     pub fn handle_event(&mut self, event: KeyEvent) {
+        let key = (event.device, event.code);
         match event.etype {
             EventType::KeyDown => {
-                if !self.keys_down.contains(&event.code) {
-                    self.keys_down.insert(event.code);
-                    self.call_event_handler(event);
+                if !self.keys_down.contains_key(&key) {
+                    let stroke = self.press(key, event.time);
+                    self.call_event_handler(event, stroke);
                 }
             },
             EventType::KeyUp => {
-                if self.keys_down.contains(&event.code) {
-                    self.keys_down.remove(&event.code);
-                    self.call_event_handler(event);
+                if let Some(stroke) = self.release(key, event.time) {
+                    self.call_event_handler(event, stroke);
+                }
+            },
+            EventType::KeyRepeat => {
+                if let Some(&(_, stroke)) = self.keys_down.get(&key) {
+                    self.call_event_handler(event, stroke);
                 }
             },
             EventType::FlagsChanged if self.options.modifier_keys => {
-                if !self.keys_down.contains(&event.code) {
-                    self.keys_down.insert(event.code);
-                    self.call_event_handler(KeyEvent {etype: EventType::KeyDown, .. event});
-                } else {
-                    self.keys_down.remove(&event.code);
-                    self.call_event_handler(KeyEvent {etype: EventType::KeyUp, .. event});
+                if !self.keys_down.contains_key(&key) {
+                    let stroke = self.press(key, event.time);
+                    self.call_event_handler(KeyEvent {etype: EventType::KeyDown, .. event}, stroke);
+                } else if let Some(stroke) = self.release(key, event.time) {
+                    self.call_event_handler(KeyEvent {etype: EventType::KeyUp, .. event}, stroke);
                 }
             },
             _ => (),
@@ -229,11 +390,22 @@ impl Keyboard {
 }
 
 
+/// Time from `from` to `to`, zero if `to` is earlier
+fn elapsed(from: Duration, to: Duration) -> Duration {
+    to.checked_sub(from).unwrap_or(Duration::new(0, 0))
+}
+
+
 #[cfg(test)]
 mod test {
     #![allow(non_snake_case)]
 
     use super::Keyboard;
+    use audio::{MockBackend, MockEvent};
+    use ffi::types::{EventType, Key, KeyEvent};
+    use input::VecSource;
+    use switch::Switch;
+    use std::time::Duration;
 
     #[test]
     fn keyboard_create_OK() -> () {
@@ -248,4 +420,157 @@ mod test {
         - 1_.wav
 ");
     }
+
+    #[test]
+    fn keyboard_keys_OK() -> () {
+        let keyboard = Keyboard::new().load_config_yaml("switches:
+   -  keys: [space, Enter]
+      keydown_paths: []
+   -  keycode_regex: '\\d+'
+      keydown_paths: []
+").unwrap();
+        let event = |key: Key| KeyEvent {
+            etype: EventType::KeyDown, code: key.native_code().unwrap(), key: key, device: 0,
+            time: Duration::new(0, 0)
+        };
+
+        assert_eq!(keyboard.get_switch_index(&event(Key::Space)), Some(0));
+        assert_eq!(keyboard.get_switch_index(&event(Key::Enter)), Some(0));
+        assert_eq!(keyboard.get_switch_index(&event(Key::A)), Some(1));
+        assert!(Keyboard::new().load_config_yaml("switches: [{keys: [Spacebar]}]").is_err());
+    }
+
+    #[test]
+    fn keyboard_keys_down_per_device_OK() -> () {
+        let mut keyboard = Keyboard::new();
+        let event = |etype, device| KeyEvent {
+            etype: etype, code: 30, key: Key::A, device: device, time: Duration::new(0, 0)
+        };
+
+        keyboard.handle_event(event(EventType::KeyDown, 0));
+        keyboard.handle_event(event(EventType::KeyDown, 1));
+        keyboard.handle_event(event(EventType::KeyUp, 0));
+        assert!(!keyboard.keys_down.contains_key(&(0, 30)));
+        assert!(keyboard.keys_down.contains_key(&(1, 30)));
+    }
+
+    #[test]
+    fn keyboard_listen_source_OK() -> () {
+        let mut keyboard = Keyboard::new();
+        let event = |etype, code| KeyEvent {
+            etype: etype, code: code, key: Key::from_evdev(code), device: 0, time: Duration::new(0, 0)
+        };
+
+        let source = VecSource::new(vec![
+            event(EventType::KeyDown, 30),
+            event(EventType::KeyDown, 31),
+            event(EventType::KeyUp, 30),
+        ]);
+        keyboard.listen(source).unwrap();
+        assert_eq!(keyboard.keys_down.keys().collect::<Vec<_>>(), vec![&(0, 31)]);
+    }
+
+    #[test]
+    fn keyboard_switch_table_OK() -> () {
+        let keyboard = Keyboard::new()
+            .switch(Switch::new().with_keys(vec![Key::Enter]))
+            .switch(Switch::new());
+        let event = |code, key| KeyEvent {
+            etype: EventType::KeyDown, code: code, key: key, device: 0, time: Duration::new(0, 0)
+        };
+
+        let enter = Key::Enter.native_code().unwrap();
+        let space = Key::Space.native_code().unwrap();
+        assert_eq!(keyboard.get_switch_index(&event(enter, Key::Enter)), Some(0));
+        assert_eq!(keyboard.get_switch_index(&event(space, Key::Space)), Some(1));
+        assert_eq!(keyboard.get_switch_index(&event(space, Key::Enter)), Some(0));
+        assert_eq!(keyboard.get_switch_index(&event(0xffff, Key::Enter)), Some(0));
+    }
+
+    #[test]
+    fn keyboard_dynamics_OK() -> () {
+        let mut keyboard = Keyboard::new().load_config_yaml("switches: []
+dynamics:
+  velocity: [[0, 0.5], [100, 1.0]]
+").unwrap();
+        let event = |etype, code, ms| KeyEvent {
+            etype: etype, code: code, key: Key::from_evdev(code), device: 0,
+            time: Duration::from_millis(ms)
+        };
+
+        keyboard.handle_event(event(EventType::KeyDown, 30, 1000));
+        keyboard.handle_event(event(EventType::KeyDown, 31, 1050));
+        assert_eq!(keyboard.keys_down[&(0, 30)].1.velocity, 1.0);
+        assert_eq!(keyboard.keys_down[&(0, 31)].1.velocity, 0.75);
+    }
+
+    #[test]
+    fn keyboard_backend_OK() -> () {
+        let backend = MockBackend::new();
+        let log = backend.log();
+        let mut keyboard = Keyboard::new().with_backend(Box::new(backend)).load_config_yaml("
+switches:
+   -  keys: [Space]
+      keydown_paths: [space.wav]
+   -  groups: [alpha]
+      keydown_paths: [down.wav]
+      keyup_paths: [up.wav, down.wav]
+      selection: round-robin
+").unwrap();
+        let event = |etype, key: Key| KeyEvent {
+            etype: etype, code: key.native_code().unwrap(), key: key, device: 0,
+            time: Duration::new(0, 0)
+        };
+
+        keyboard.handle_event(event(EventType::KeyDown, Key::A));
+        keyboard.handle_event(event(EventType::KeyDown, Key::Space));
+        keyboard.handle_event(event(EventType::KeyUp, Key::A));
+
+        let played: Vec<_> = log.borrow().iter()
+            .filter_map(|event| match *event {
+                MockEvent::Play { ref sample, .. } => Some(sample.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(played, vec!["down.wav", "space.wav", "up.wav"]);
+        assert_eq!(log.borrow().iter().filter(|event| **event == MockEvent::Load("down.wav".into())).count(), 1);
+    }
+
+    #[test]
+    fn keyboard_keyrepeat_OK() -> () {
+        let backend = MockBackend::new();
+        let log = backend.log();
+        let mut keyboard = Keyboard::new().with_backend(Box::new(backend)).load_config_yaml("
+switches:
+   -  keys: [A]
+      keydown_paths: [a_down.wav]
+   -  keys: [B]
+      keydown_paths: [b_down.wav]
+      keyrepeat: keydown
+   -  keys: [C]
+      keydown_paths: [c_down.wav]
+      keyrepeat_paths: [c_repeat.wav]
+").unwrap();
+        let event = |etype, key: Key| KeyEvent {
+            etype: etype, code: key.native_code().unwrap(), key: key, device: 0,
+            time: Duration::new(0, 0)
+        };
+
+        for &key in &[Key::A, Key::B, Key::C] {
+            keyboard.handle_event(event(EventType::KeyDown, key));
+            keyboard.handle_event(event(EventType::KeyRepeat, key));
+            keyboard.handle_event(event(EventType::KeyRepeat, key));
+        }
+        // repeats of keys that aren't down are ignored
+        keyboard.handle_event(event(EventType::KeyRepeat, Key::D));
+
+        let played: Vec<_> = log.borrow().iter()
+            .filter_map(|event| match *event {
+                MockEvent::Play { ref sample, .. } => Some(sample.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(played, vec!["a_down.wav", "b_down.wav", "b_down.wav", "b_down.wav",
+                                "c_down.wav", "c_repeat.wav", "c_repeat.wav"]);
+    }
 }
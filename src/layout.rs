@@ -0,0 +1,187 @@
+//! Physical keyboard layouts
+//!
+//! A Layout knows where every key sits on the keyboard, so clicks can
+//! be placed where the key is instead of guessing from key codes.
+//! Layouts are YAML files listing the keys of every row from left to
+//! right, see `resources/layouts`.
+//!
+//! # Example
+//! ```ignore
+//! let layout = Layout::load("hhkb", Path::new("."))?;
+//! let pan = layout.pan(Key::Enter);
+//! ```
+
+use ffi::types::Key;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use yaml_rust;
+use yaml_rust::Yaml;
+use ::errors::KeyboardError;
+
+/// The layouts shipped with modelm
+static BUILTIN_LAYOUTS: &'static [(&'static str, &'static str)] = &[
+    ("ansi", include_str!("../resources/layouts/ansi.yaml")),
+    ("iso", include_str!("../resources/layouts/iso.yaml")),
+    ("jis", include_str!("../resources/layouts/jis.yaml")),
+    ("hhkb", include_str!("../resources/layouts/hhkb.yaml")),
+    ("tkl", include_str!("../resources/layouts/tkl.yaml")),
+    ("full", include_str!("../resources/layouts/full.yaml")),
+];
+
+pub static DEFAULT_LAYOUT: &'static str = "ansi";
+
+/// Positions of keys, measured in key widths (units) from the top left
+/// corner of the keyboard
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub name: String,
+    /// Center of every key
+    keys: HashMap<Key, (f32, f32)>,
+    /// Width of the widest row
+    pub width: f32,
+    /// Number of rows
+    pub height: f32,
+}
+
+impl Layout {
+    /// Returns the builtin layout called `name`, e.g. `ansi`
+    pub fn builtin(name: &str) -> Option<Layout> {
+        BUILTIN_LAYOUTS.iter()
+            .find(|layout| layout.0 == name)
+            .map(|layout| Layout::from_yaml_str(layout.1).expect("builtin layout is invalid"))
+    }
+
+    /// Loads a builtin layout by name or a layout file, relative paths
+    /// are relative to `dir`
+    pub fn load(name: &str, dir: &Path) -> Result<Layout, KeyboardError> {
+        if let Some(layout) = Layout::builtin(name) {
+            return Ok(layout);
+        }
+
+        let mut config = String::new();
+        let mut file = try!(File::open(dir.join(name)));
+        try!(file.read_to_string(&mut config));
+        Layout::from_yaml_str(&config)
+    }
+
+    pub fn from_yaml_str(config: &str) -> Result<Layout, KeyboardError> {
+        let parsed = try!(yaml_rust::YamlLoader::load_from_str(config));
+        let yaml = &parsed[0];
+
+        let name = try_yaml!(yaml["name"], Yaml::String, "layout must have String [name]");
+        let rows = try_yaml!(yaml["rows"], Yaml::Array, "layout must have Array [rows]");
+
+        let mut layout = Layout {
+            name: name.clone(),
+            keys: HashMap::new(),
+            width: 0.0,
+            height: rows.len() as f32,
+        };
+
+        for (y, row) in rows.iter().enumerate() {
+            let row = try_yaml!(*row, Yaml::Array, "layout rows must be Arrays");
+
+            let mut x = 0.0;
+            for key in row {
+                let (names, width) = try!(parse_key(key));
+
+                for name in names.split('|').filter(|name| *name != "_") {
+                    layout.keys.insert(try!(name.parse()), (x + width / 2.0, y as f32 + 0.5));
+                }
+                x += width;
+            }
+            layout.width = layout.width.max(x);
+        }
+
+        debug!("Parsed layout {} with {} keys", layout.name, layout.keys.len());
+        Ok(layout)
+    }
+
+    /// Returns the center of `key`, None if the layout does not have it
+    pub fn position(&self, key: Key) -> Option<(f32, f32)> {
+        self.keys.get(&key).cloned()
+    }
+
+    /// Returns where `key` is from left (-1.0) to right (1.0), keys the
+    /// layout does not have are in the middle
+    pub fn pan(&self, key: Key) -> f32 {
+        match self.position(key) {
+            Some((x, _)) if self.width > 0.0 => x / self.width * 2.0 - 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Returns how far back `key` is from the front row (0.0) to the
+    /// back row (1.0), keys the layout does not have are in the middle
+    pub fn depth(&self, key: Key) -> f32 {
+        match self.position(key) {
+            Some((_, y)) if self.height > 0.0 => 1.0 - y / self.height,
+            _ => 0.5,
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self
+    {
+        Layout::builtin(DEFAULT_LAYOUT).unwrap()
+    }
+}
+
+
+/// Parses a layout key, either a name like `Escape` or a name and its
+/// width like `Backspace:2`, which YAML reads as a Hash
+fn parse_key(key: &Yaml) -> Result<(&str, f32), KeyboardError> {
+    let error = || KeyboardError::Config(format!("Unable to parse layout key: {:?}", key));
+
+    match *key {
+        Yaml::String(ref name) => Ok((name, 1.0)),
+        Yaml::Hash(ref hash) if hash.len() == 1 => {
+            let (name, width) = hash.iter().next().unwrap();
+            let width = match *width {
+                Yaml::Real(_) => try!(width.as_f64().ok_or_else(&error)),
+                Yaml::Integer(width) => width as f64,
+                _ => return Err(error()),
+            };
+            Ok((try!(name.as_str().ok_or_else(&error)), width as f32))
+        },
+        _ => Err(error()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{Layout, BUILTIN_LAYOUTS};
+    use ffi::types::Key;
+
+    #[test]
+    fn layout_builtin_OK() -> () {
+        for &(name, _) in BUILTIN_LAYOUTS {
+            let layout = Layout::builtin(name).unwrap();
+            assert!(layout.position(Key::Space).is_some(), "{} has no space", name);
+        }
+    }
+
+    #[test]
+    fn layout_rows_OK() -> () {
+        let layout = Layout::from_yaml_str("name: test
+rows:
+  - [Escape, _:0.5, F1|Fn]
+  - [Tab:1.5, Q]
+").unwrap();
+        assert_eq!(layout.position(Key::Escape), Some((0.5, 0.5)));
+        assert_eq!(layout.position(Key::Fn), Some((2.0, 0.5)));
+        assert_eq!(layout.position(Key::Q), Some((2.0, 1.5)));
+        assert_eq!(layout.width, 2.5);
+        assert!((layout.pan(Key::Q) - 0.6).abs() < 1e-6);
+        assert_eq!(layout.pan(Key::Space), 0.0);
+        assert_eq!(layout.depth(Key::Escape), 0.75);
+        assert_eq!(layout.depth(Key::Q), 0.25);
+        assert!(Layout::from_yaml_str("name: test\nrows: [[Tab:wide]]").is_err());
+    }
+}
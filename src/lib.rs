@@ -5,8 +5,15 @@ extern crate quick_error;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "cpal")]
+extern crate cpal;
+#[cfg(feature = "openal")]
 extern crate ears;
+extern crate claxon;
+extern crate hound;
+extern crate lewton;
 extern crate libc;
+extern crate minimp3;
 extern crate rand;
 extern crate regex;
 extern crate yaml_rust;
@@ -15,7 +22,18 @@ extern crate yaml_rust;
 pub mod macros;
 pub mod keyboard;
 pub mod ffi;
+pub mod input;
+pub mod layout;
+pub mod calibrate;
 pub mod switch;
+pub mod selection;
+pub mod dynamics;
+pub mod voice;
+pub mod audio;
+pub mod render;
+pub mod typist;
+pub mod session;
+pub mod terminal;
 pub mod errors;
 
-static DEFAULT_SOUND_FILE_REGEX: &'static str = r"\.(wav|mp3)";
+static DEFAULT_SOUND_FILE_REGEX: &'static str = r"\.(wav|flac|ogg|mp3)";
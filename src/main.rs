@@ -4,20 +4,38 @@
 #[macro_use]
 extern crate log;
 extern crate modelm;
-extern crate ears;
 extern crate env_logger;
 extern crate clap;
 
-use clap::{Arg, App, ArgMatches};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use modelm::audio::{self, AudioBackend, MixerBackend};
+use modelm::calibrate::{calibrate, ROW_PAUSE_MS, DONE_PAUSE_MS};
+use modelm::errors::KeyboardError;
+use modelm::ffi::NativeSource;
 use modelm::keyboard::{Keyboard, KeyboardOptions};
+use modelm::layout::Layout;
+use modelm::render::{self, render_wav};
+use modelm::session::{self, ReplaySource};
+use modelm::input::VecSource;
+use modelm::terminal::{PipeSource, TerminalSource};
+use modelm::typist::Typist;
 use std::env;
-use std::path::Path;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+use std::io::{self, BufReader};
 use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 static DEFAULT_PATH: &'static str = "resources/modelm";
 static DEFAULT_CONFIG_PATH: &'static str = "config.yaml";
 
+/// How long to keep running after a replay or pipe ends so the last
+/// sound finishes
+const TAIL_MS: u64 = 1000;
+
 /// Setup logging (cli arg overwrites env var for dtt crate)
 pub fn setup_logging(matches: &ArgMatches)
 {
@@ -34,11 +52,48 @@ pub fn setup_logging(matches: &ArgMatches)
     debug!("Set log level to {}", log_level);
 }
 
-fn main() {
-    if let Err(error) = ears::init() {
-        return error!("{}", error)
+/// Turns on HRTF in OpenAL Soft by pointing it at a config file that
+/// enables it on top of the one in ALSOFT_CONF, has to run before OpenAL
+/// is initialized. Returns the file, which can be removed once it is.
+fn enable_hrtf() -> io::Result<PathBuf> {
+    let mut config = String::new();
+    if let Ok(previous) = env::var("ALSOFT_CONF") {
+        try!(try!(File::open(&previous)).read_to_string(&mut config));
+        config.push('\n');
     }
+    config.push_str("[general]\nhrtf = true\n");
+
+    // only ever create a new file that nobody else can read, so nothing
+    // placed at the path beforehand gets written through
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+    let mut attempt = 0;
+    let (path, mut file) = loop {
+        let path = env::temp_dir().join(format!("modelm-alsoft-{}-{}-{}.conf", process::id(), nanos, attempt));
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => break (path, file),
+            Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(error) => return Err(error),
+        }
+    };
+    try!(file.write_all(config.as_bytes()));
+
+    env::set_var("ALSOFT_CONF", &path);
+    Ok(path)
+}
 
+/// Parses a comma separated position like `0,0.5,0`
+fn parse_position(position: &str) -> Option<[f32; 3]> {
+    let coords: Vec<f32> = match position.split(',').map(|c| c.trim().parse()).collect() {
+        Ok(coords) => coords,
+        Err(_) => return None,
+    };
+    match coords.len() {
+        3 => Some([coords[0], coords[1], coords[2]]),
+        _ => None,
+    }
+}
+
+fn main() {
     let matches = App::new("modelm")
         .version("0.5.0")
         .author("Joshua Miller <jsmiller@uchicago.edu>")
@@ -67,6 +122,36 @@ fn main() {
              .short("m")
              .long("with-modifier-keys")
              .help("Don't exclude modifier keys (control, alt, shift, etc.)"))
+        .arg(Arg::with_name("TERMINAL")
+             .short("t")
+             .long("terminal")
+             .help("Listen to keys typed in this terminal instead of the keyboard \
+                    devices, no root needed"))
+        .arg(Arg::with_name("PIPE")
+             .short("p")
+             .long("pipe")
+             .help("Pass stdin through to stdout, clicking for every character"))
+        .arg(Arg::with_name("LAYOUT")
+             .short("l")
+             .long("layout")
+             .help("Specify the physical layout used to place clicks, one of ansi, \
+                    iso, jis, hhkb, tkl, full or a layout file (default: the \
+                    layout of the config or ansi)")
+             .takes_value(true))
+        .arg(Arg::with_name("LISTENER")
+             .long("listener")
+             .help("Specify where you are as X,Y,Z (default: 0,0,0). The keyboard \
+                    lies in front of the origin, from 1.0 to 1.3 towards -Z.")
+             .takes_value(true))
+        .arg(Arg::with_name("BACKEND")
+             .short("b")
+             .long("backend")
+             .help("Specify how to play sounds: openal (default) or cpal, if \
+                    compiled in, or null to play nothing")
+             .takes_value(true))
+        .arg(Arg::with_name("HRTF")
+             .long("hrtf")
+             .help("Use HRTF for 3D sound on headphones (OpenAL Soft only)"))
         .arg(Arg::with_name("XSCALE")
              .short("x")
              .long("x-scale")
@@ -75,10 +160,112 @@ fn main() {
                     apart the clicks will sound. A value of 0 turns off positional \
                     sound. A value < 0 reverses the directionality.")
              .takes_value(true))
+        .subcommand(SubCommand::with_name("record")
+                    .about("Records keystrokes to a session log while playing them")
+                    .arg(Arg::with_name("FILE")
+                         .help("The session log to write")
+                         .required(true)
+                         .index(1)))
+        .subcommand(SubCommand::with_name("calibrate")
+                    .about("Writes a layout file for your keyboard from the keys you press")
+                    .arg(Arg::with_name("FILE")
+                         .help("The layout file to write")
+                         .required(true)
+                         .index(1))
+                    .arg(Arg::with_name("REPLAY")
+                         .long("replay")
+                         .help("Read the key presses from a session log, - for stdin")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("render")
+                    .about("Mixes the clicks of a session log into a WAV file instead of \
+                            playing them")
+                    .arg(Arg::with_name("EVENTS")
+                         .long("events")
+                         .help("The session log to read, - for stdin")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("OUT")
+                         .long("out")
+                         .help("The WAV file to write")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("type")
+                    .about("Types text on the keyboard, pressing shift for capitals")
+                    .arg(Arg::with_name("TEXT")
+                         .help("The text to type, - for stdin")
+                         .required(true)
+                         .index(1))
+                    .arg(Arg::with_name("WPM")
+                         .long("wpm")
+                         .help("How fast to type in words per minute (default: 60)")
+                         .takes_value(true))
+                    .arg(Arg::with_name("SEED")
+                         .long("seed")
+                         .help("Type the same way every time")
+                         .takes_value(true))
+                    .arg(Arg::with_name("OUT")
+                         .long("out")
+                         .help("Mix the clicks into this WAV file instead of playing them")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("replay")
+                    .about("Plays back a session log written by record")
+                    .arg(Arg::with_name("FILE")
+                         .help("The session log to read, - for stdin")
+                         .required(true)
+                         .index(1)))
         .get_matches();
 
     setup_logging(&matches);
 
+    let backend = matches.value_of("BACKEND").unwrap_or(audio::backends()[0]);
+
+    let mut hrtf_conf = None;
+    if matches.is_present("HRTF") {
+        if backend != "openal" {
+            warn!("HRTF is only available with the openal backend");
+        } else {
+            match enable_hrtf() {
+                Ok(path) => hrtf_conf = Some(path),
+                Err(error) => error!("Unable to enable HRTF: {:?}", error),
+            }
+        }
+    }
+
+    // render and type --out mix into a file instead of playing
+    let renders = match matches.subcommand() {
+        ("render", _) => true,
+        ("type", Some(type_matches)) => type_matches.is_present("OUT"),
+        _ => false,
+    };
+    let (backend, mixer): (Box<dyn AudioBackend>, _) = match renders {
+        true => {
+            let backend = MixerBackend::new(render::SAMPLE_RATE);
+            let mixer = backend.mixer();
+            (Box::new(backend), Some(mixer))
+        },
+        false => {
+            // a build without openal or cpal would otherwise play nothing
+            // without saying so
+            if backend == "null" && !matches.is_present("BACKEND") {
+                return error!("No audio backend compiled in, build with the openal or cpal \
+                               feature, or pass --backend null to play nothing");
+            }
+            let opened = audio::open(backend);
+            // OpenAL has read its config
+            if let Some(path) = hrtf_conf {
+                let _ = fs::remove_file(path);
+            }
+            match opened {
+                Ok(backend) => (backend, None),
+                Err(error) => return error!("Unable to open audio backend: {:?}", error),
+            }
+        },
+    };
+
+    // session logs are relative to where we were started, not the
+    // sound directory
+    let cwd = env::current_dir().expect("unable to read current directory");
+
     // working directory
     let dir = matches.value_of("DIR").unwrap_or(DEFAULT_PATH);
 
@@ -98,6 +285,10 @@ fn main() {
     let x_scale: f32 = matches.value_of("XSCALE").unwrap_or("1.0").parse()
         .expect("x-scale must be a decimal. (default: 1.0)");
 
+    // listener
+    let listener_position = parse_position(matches.value_of("LISTENER").unwrap_or("0,0,0"))
+        .expect("listener must be three decimals X,Y,Z (default: 0,0,0)");
+
     // Read the config file
     let mut config = String::new();
     let mut config_file = File::open(&config_path)
@@ -111,15 +302,136 @@ fn main() {
         x_scale: x_scale,
         volume: volume,
         modifier_keys: matches.is_present("MODIFIER_KEYS"),
+        listener_position: listener_position,
+        .. KeyboardOptions::default()
     };
 
     let keyboard = Keyboard::with_options(options)
+        .with_backend(backend)
         .load_config_yaml(&*config);
 
+    let mut keyboard = match keyboard {
+        Ok(keyboard) => keyboard,
+        Err(error) => return error!("Unable to initialize keyboard: {:?}", error),
+    };
+
+    // layout, overrides the one of the config
+    if let Some(layout) = matches.value_of("LAYOUT") {
+        keyboard = match Layout::load(layout, &cwd) {
+            Ok(layout) => keyboard.set_layout(layout),
+            Err(error) => return error!("Unable to load layout {}: {:?}", layout, error),
+        };
+    }
+
     // Run the keyboard
-    match keyboard {
-        Ok(mut keyboard) => keyboard.listen(),
-        Err(error) => error!("Unable to initialize keyboard: {:?}", error),
+    let result = match matches.subcommand() {
+        ("record", Some(record_matches)) => {
+            let path = cwd.join(record_matches.value_of("FILE").unwrap());
+            info!("Recording to {:?}", path);
+            File::create(&path)
+                .map_err(KeyboardError::from)
+                .and_then(|file| if matches.is_present("PIPE") {
+                    keyboard.record(PipeSource::new(), file)
+                } else if matches.is_present("TERMINAL") {
+                    keyboard.record(TerminalSource::new(), file)
+                } else {
+                    keyboard.record(NativeSource::new(), file)
+                })
+        },
+        ("calibrate", Some(calibrate_matches)) => {
+            let path = cwd.join(calibrate_matches.value_of("FILE").unwrap());
+            let name = path.file_stem().map_or("Custom".into(), |stem| stem.to_string_lossy());
+            let layout = match calibrate_matches.value_of("REPLAY") {
+                Some("-") => calibrate(ReplaySource::new(BufReader::new(io::stdin())).untimed(), &name),
+                Some(log) => File::open(&cwd.join(log))
+                    .map_err(KeyboardError::from)
+                    .and_then(|file| calibrate(ReplaySource::new(BufReader::new(file)).untimed(), &name)),
+                None => {
+                    println!("Press every key of the back row from left to right, wait {:.1}s, \
+                              and go on with the next row. Stop for {:.1}s when you are done.",
+                             ROW_PAUSE_MS as f32 / 1000.0, DONE_PAUSE_MS as f32 / 1000.0);
+                    match matches.is_present("TERMINAL") {
+                        true => calibrate(TerminalSource::new(), &name),
+                        false => calibrate(NativeSource::new(), &name),
+                    }
+                },
+            };
+            layout.and_then(|layout| {
+                info!("Writing layout to {:?}", path);
+                try!(try!(File::create(&path)).write_all(layout.as_bytes()));
+                Ok(())
+            })
+        },
+        ("render", Some(render_matches)) => {
+            let out = cwd.join(render_matches.value_of("OUT").unwrap());
+            let events = match render_matches.value_of("EVENTS").unwrap() {
+                "-" => session::read_events(BufReader::new(io::stdin())),
+                path => File::open(&cwd.join(path))
+                    .map_err(KeyboardError::from)
+                    .and_then(|file| session::read_events(BufReader::new(file))),
+            };
+            events.and_then(|events| {
+                info!("Rendering {} events to {:?}", events.len(), out);
+                let mixer = mixer.expect("render plays through a mixer");
+                render_wav(&mut keyboard, &mixer, events, &out)
+            })
+        },
+        ("type", Some(type_matches)) => {
+            let wpm: f32 = type_matches.value_of("WPM").unwrap_or("60").parse()
+                .expect("wpm must be a decimal (default: 60)");
+            let mut typist = match type_matches.value_of("SEED") {
+                Some(seed) => Typist::with_seed(wpm, seed.parse().expect("seed must be a positive integer")),
+                None => Typist::new(wpm),
+            };
+            let text = match type_matches.value_of("TEXT").unwrap() {
+                "-" => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text).map(|_| text)
+                },
+                text => Ok(text.to_string()),
+            };
+
+            text.map_err(KeyboardError::from).and_then(|text| {
+                let events = typist.type_text(&text);
+                match type_matches.value_of("OUT") {
+                    Some(out) => {
+                        let out = cwd.join(out);
+                        info!("Rendering {} events to {:?}", events.len(), out);
+                        let mixer = mixer.expect("type --out plays through a mixer");
+                        render_wav(&mut keyboard, &mixer, events, &out)
+                    },
+                    None => {
+                        let result = keyboard.listen(VecSource::new(events).timed());
+                        thread::sleep(Duration::from_millis(TAIL_MS));
+                        result
+                    },
+                }
+            })
+        },
+        ("replay", Some(matches)) => {
+            let result = match matches.value_of("FILE").unwrap() {
+                "-" => keyboard.listen(ReplaySource::new(BufReader::new(io::stdin()))),
+                path => {
+                    let path = cwd.join(path);
+                    info!("Replaying {:?}", path);
+                    File::open(&path)
+                        .map_err(KeyboardError::from)
+                        .and_then(|file| keyboard.listen(ReplaySource::new(BufReader::new(file))))
+                },
+            };
+            thread::sleep(Duration::from_millis(TAIL_MS));
+            result
+        },
+        _ if matches.is_present("PIPE") => {
+            let result = keyboard.listen(PipeSource::new());
+            thread::sleep(Duration::from_millis(TAIL_MS));
+            result
+        },
+        _ if matches.is_present("TERMINAL") => keyboard.listen(TerminalSource::new()),
+        _ => keyboard.listen(NativeSource::new()),
     };
 
+    if let Err(error) = result {
+        error!("Keyboard failed: {:?}", error)
+    }
 }
@@ -0,0 +1,139 @@
+//! Rendering session logs to sound files
+//!
+//! Plays the events of a session log on a Keyboard that plays through a
+//! `MixerBackend`, mixing the clicks at the times of their events
+//! instead of as they happen. No sound device is needed, and the mix is
+//! written out as it goes instead of kept in memory.
+//!
+//! # Example
+//! ```ignore
+//! let backend = MixerBackend::new(render::SAMPLE_RATE);
+//! let mixer = backend.mixer();
+//! let mut keyboard = Keyboard::new().with_backend(Box::new(backend)).load_config_yaml(config)?;
+//! render::render_wav(&mut keyboard, &mixer, session::read_events(log)?, Path::new("typing.wav"))?;
+//! ```
+
+use audio::decode::WavFile;
+use audio::Mixer;
+use audio::mixer::lock;
+use ffi::types::KeyEvent;
+use keyboard::Keyboard;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use ::errors::KeyboardError;
+
+/// Sample rate of rendered files
+pub const SAMPLE_RATE: u32 = 44100;
+/// Rendered files are stereo
+pub const CHANNELS: usize = 2;
+/// How long to keep mixing after the last event at most, for the sounds
+/// still playing
+const MAX_TAIL_SECS: u32 = 10;
+/// Frames mixed at once
+const BLOCK_FRAMES: usize = 1024;
+
+/// Handles `events` on `keyboard`, which plays through `mixer`, and
+/// passes the mixed stereo frames to `write` a block at a time, starting
+/// at the first event and ending when the last sound has finished
+pub fn render<F>(keyboard: &mut Keyboard, mixer: &Mutex<Mixer>, events: Vec<KeyEvent>, mut write: F)
+                 -> Result<(), KeyboardError>
+    where F: FnMut(&[f32]) -> Result<(), KeyboardError>
+{
+    let sample_rate = lock(mixer).sample_rate();
+    let start = match events.first() {
+        Some(event) => event.time,
+        None => return Ok(()),
+    };
+
+    let mut block = vec![0.0; BLOCK_FRAMES * CHANNELS];
+    let mut mixed = 0;
+    keyboard.place_listener();
+
+    for event in events {
+        // mix up to the time of the event, events out of order are late
+        let end = frame_at(event.time.checked_sub(start).unwrap_or(Duration::new(0, 0)), sample_rate);
+        while mixed < end {
+            let frames = (end - mixed).min(BLOCK_FRAMES);
+            let part = &mut block[..frames * CHANNELS];
+            lock(mixer).mix(part, CHANNELS);
+            try!(write(part));
+            mixed += frames;
+        }
+        keyboard.handle_event(event);
+    }
+
+    let tail_end = mixed + (MAX_TAIL_SECS * sample_rate) as usize;
+    while lock(mixer).is_playing() && mixed < tail_end {
+        lock(mixer).mix(&mut block, CHANNELS);
+        try!(write(&block));
+        mixed += BLOCK_FRAMES;
+    }
+    Ok(())
+}
+
+
+/// Renders `events` like `render` to a 16 bit stereo WAV file at `path`
+pub fn render_wav(keyboard: &mut Keyboard, mixer: &Mutex<Mixer>, events: Vec<KeyEvent>, path: &Path)
+                  -> Result<(), KeyboardError> {
+    let sample_rate = lock(mixer).sample_rate();
+    let mut file = try!(WavFile::create(path, CHANNELS, sample_rate));
+    try!(render(keyboard, mixer, events, |frames| file.write(frames)));
+    file.finalize()
+}
+
+
+/// The index of the frame `time` after the start
+fn frame_at(time: Duration, sample_rate: u32) -> usize {
+    (time.as_secs() * sample_rate as u64
+     + time.subsec_nanos() as u64 * sample_rate as u64 / 1_000_000_000) as usize
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{render, CHANNELS, SAMPLE_RATE};
+    use audio::MixerBackend;
+    use audio::decode::write_wav;
+    use ffi::types::{EventType, Key, KeyEvent};
+    use keyboard::Keyboard;
+    use std::{env, fs, process};
+    use std::time::Duration;
+
+    #[test]
+    fn render_session_OK() -> () {
+        // a 10ms click
+        let click = env::temp_dir().join(format!("modelm-render-click-{}.wav", process::id()));
+        write_wav(&click, &vec![0.5; 441 * CHANNELS], CHANNELS, SAMPLE_RATE).unwrap();
+
+        let backend = MixerBackend::new(SAMPLE_RATE);
+        let mixer = backend.mixer();
+        let keyboard = Keyboard::new().with_backend(Box::new(backend))
+            .load_config_yaml(&format!("switches: [{{keys: [A, L], keydown_paths: [{:?}]}}]", click));
+        fs::remove_file(&click).unwrap();
+        let mut keyboard = keyboard.unwrap();
+        let event = |etype, key: Key, ms: u64| KeyEvent {
+            etype: etype, code: key.native_code().unwrap(), key: key, device: 0,
+            time: Duration::from_millis(1000 + ms)
+        };
+
+        let mut frames = vec![];
+        render(&mut keyboard, &mixer, vec![
+            event(EventType::KeyDown, Key::A, 0),
+            event(EventType::KeyUp, Key::A, 50),
+            event(EventType::KeyDown, Key::L, 500),
+        ], |block| {
+            frames.extend_from_slice(block);
+            Ok(())
+        }).unwrap();
+
+        // A is left of L, both start on time and the file ends after L
+        let frame = |ms: usize| (frames[ms * 441 / 10 * CHANNELS], frames[ms * 441 / 10 * CHANNELS + 1]);
+        assert!(frame(0).0 > frame(0).1 && frame(0).1 > 0.0);
+        assert_eq!(frame(100), (0.0, 0.0));
+        assert!(frame(500).0 < frame(500).1);
+        assert!(frames.len() >= 510 * 441 / 10 * CHANNELS && frames.len() < 600 * 441 / 10 * CHANNELS);
+    }
+}
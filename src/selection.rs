@@ -0,0 +1,244 @@
+//! Choosing which sample to play
+//!
+//! A switch usually has several samples per action so repeated keys
+//! don't sound identical. A Selector decides which one plays next.
+
+use rand::{Rng, SeedableRng, StdRng, thread_rng};
+use rand::distributions::{IndependentSample, Range};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use ::errors::KeyboardError;
+
+/// How a Selector picks the next sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Any sample, chosen at random by weight
+    Uniform,
+    /// Every sample once in random order, then again in a new order
+    ShuffleBag,
+    /// Any sample but the last N played, chosen at random by weight
+    AvoidLast(usize),
+    /// Every sample in order
+    RoundRobin,
+}
+
+impl FromStr for Strategy {
+    type Err = KeyboardError;
+
+    /// Parses `uniform`, `shuffle-bag`, `round-robin`, `avoid-last` or
+    /// `avoid-last N`
+    fn from_str(s: &str) -> Result<Strategy, KeyboardError> {
+        let error = || KeyboardError::Config(format!(
+            "selection must be one of uniform, shuffle-bag, avoid-last [N] or round-robin, not {}", s));
+
+        let mut words = s.split_whitespace();
+        let strategy = match words.next() {
+            Some("uniform") => Strategy::Uniform,
+            Some("shuffle-bag") => Strategy::ShuffleBag,
+            Some("round-robin") => Strategy::RoundRobin,
+            Some("avoid-last") => match words.next() {
+                Some(n) => Strategy::AvoidLast(try!(n.parse().map_err(|_| error()))),
+                None => Strategy::AvoidLast(1),
+            },
+            _ => return Err(error()),
+        };
+
+        match words.next() {
+            Some(_) => Err(error()),
+            None => Ok(strategy),
+        }
+    }
+}
+
+
+/// Returns a random number generator, the same numbers every time for
+/// the same `seed`
+pub fn rng(seed: Option<usize>) -> StdRng {
+    let seed = seed.unwrap_or_else(|| thread_rng().gen());
+    SeedableRng::from_seed(&[seed][..])
+}
+
+
+/// Picks samples from a list according to a Strategy
+pub struct Selector {
+    strategy: Strategy,
+    rng: StdRng,
+    /// Most recently played first
+    history: VecDeque<usize>,
+    bag: Vec<usize>,
+    next: usize,
+}
+
+impl Selector {
+    pub fn new(strategy: Strategy) -> Selector
+    {
+        Selector::with_rng(strategy, rng(None))
+    }
+
+    /// Creates a Selector that makes the same choices every time
+    pub fn with_seed(strategy: Strategy, seed: usize) -> Selector
+    {
+        Selector::with_rng(strategy, rng(Some(seed)))
+    }
+
+    fn with_rng(strategy: Strategy, rng: StdRng) -> Selector
+    {
+        Selector {
+            strategy: strategy,
+            rng: rng,
+            history: VecDeque::new(),
+            bag: vec![],
+            next: 0,
+        }
+    }
+
+    /// Returns the index of the next sample out of `len`, `weight`
+    /// gives the weight of each index. Random strategies prefer heavier
+    /// samples, and samples without weight are never played. None if
+    /// there is nothing to play.
+    pub fn select<F: Fn(usize) -> f32>(&mut self, len: usize, weight: F) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+
+        let index = match self.strategy {
+            Strategy::Uniform => self.weighted(len, &weight, 0),
+            Strategy::AvoidLast(n) => {
+                let avoid = n.min(len - 1);
+                self.weighted(len, &weight, avoid)
+            },
+            Strategy::ShuffleBag => self.from_bag(len, &weight),
+            Strategy::RoundRobin => {
+                let start = self.next;
+                let index = (start..start + len).map(|i| i % len).find(|&i| weight(i) > 0.0);
+                self.next = index.map_or(start, |index| index + 1);
+                index
+            },
+        };
+
+        if let Some(index) = index {
+            self.history.push_front(index);
+            self.history.truncate(len);
+        }
+        index
+    }
+
+    /// Picks an index at random by weight, skipping the `avoid` most
+    /// recently played unless only they have weight
+    fn weighted<F: Fn(usize) -> f32>(&mut self, len: usize, weight: &F, avoid: usize)
+                                     -> Option<usize> {
+        let history = &self.history;
+        let candidates: Vec<(usize, f32)> = (0..len)
+            .filter(|i| !history.iter().take(avoid).any(|played| played == i))
+            .map(|i| (i, weight(i).max(0.0)))
+            .collect();
+
+        let total: f32 = candidates.iter().map(|c| c.1).sum();
+        if total <= 0.0 {
+            return match avoid {
+                0 => None,
+                _ => self.weighted(len, weight, 0),
+            };
+        }
+
+        let mut target = Range::new(0.0, total).ind_sample(&mut self.rng);
+        for &(i, weight) in &candidates {
+            if target < weight {
+                return Some(i);
+            }
+            target -= weight;
+        }
+        candidates.iter().rev().find(|c| c.1 > 0.0).map(|c| c.0)
+    }
+
+    /// Takes the next index with weight out of the bag, refilling it in
+    /// a new order once empty
+    fn from_bag<F: Fn(usize) -> f32>(&mut self, len: usize, weight: &F) -> Option<usize> {
+        if !self.bag.iter().any(|&i| i < len && weight(i) > 0.0) {
+            self.bag.clear();
+        }
+
+        if self.bag.is_empty() || self.bag.iter().any(|&i| i >= len) {
+            self.bag = (0..len).collect();
+            self.rng.shuffle(&mut self.bag);
+
+            // don't play the same sample twice across refills
+            if len > 1 && self.bag.last() == self.history.front() {
+                self.bag.swap(0, len - 1);
+            }
+        }
+
+        match self.bag.iter().rposition(|&i| weight(i) > 0.0) {
+            Some(position) => Some(self.bag.remove(position)),
+            None => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{Selector, Strategy};
+
+    #[test]
+    fn selection_strategy_parse_OK() -> () {
+        assert_eq!("shuffle-bag".parse::<Strategy>().unwrap(), Strategy::ShuffleBag);
+        assert_eq!("avoid-last".parse::<Strategy>().unwrap(), Strategy::AvoidLast(1));
+        assert_eq!("avoid-last 3".parse::<Strategy>().unwrap(), Strategy::AvoidLast(3));
+        assert!("avoid-last many".parse::<Strategy>().is_err());
+        assert!("random".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn selection_strategies_OK() -> () {
+        let select = |strategy, n| {
+            let mut selector = Selector::with_seed(strategy, 7);
+            (0..n).map(|_| selector.select(3, |_| 1.0).unwrap()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(select(Strategy::RoundRobin, 4), vec![0, 1, 2, 0]);
+        assert_eq!(select(Strategy::Uniform, 20), select(Strategy::Uniform, 20));
+
+        let bag = select(Strategy::ShuffleBag, 12);
+        for chunk in bag.chunks(3) {
+            let mut chunk = chunk.to_vec();
+            chunk.sort();
+            assert_eq!(chunk, vec![0, 1, 2]);
+        }
+
+        let avoided = select(Strategy::AvoidLast(2), 20);
+        for window in avoided.windows(3) {
+            assert!(window[0] != window[1] && window[0] != window[2] && window[1] != window[2]);
+        }
+    }
+
+    #[test]
+    fn selection_weights_OK() -> () {
+        let mut selector = Selector::with_seed(Strategy::Uniform, 7);
+        for _ in 0..20 {
+            assert_eq!(selector.select(3, |i| if i == 1 { 1.0 } else { 0.0 }), Some(1));
+        }
+        assert_eq!(selector.select(3, |_| 0.0), None);
+        assert_eq!(selector.select(0, |_| 1.0), None);
+
+        for strategy in vec![Strategy::ShuffleBag, Strategy::RoundRobin] {
+            let mut selector = Selector::with_seed(strategy, 7);
+            for _ in 0..5 {
+                assert!(selector.select(3, |i| if i == 1 { 0.0 } else { 1.0 }) != Some(1));
+            }
+            assert_eq!(selector.select(3, |_| 0.0), None);
+        }
+    }
+
+    #[test]
+    fn selection_avoid_last_fallback_OK() -> () {
+        // the one sample with weight plays even though it was just played
+        let mut selector = Selector::with_seed(Strategy::AvoidLast(2), 7);
+        for _ in 0..5 {
+            assert_eq!(selector.select(3, |i| if i == 2 { 1.0 } else { 0.0 }), Some(2));
+        }
+        assert_eq!(selector.select(3, |_| 0.0), None);
+    }
+}
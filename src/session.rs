@@ -0,0 +1,193 @@
+//! Recording and replaying keystroke sessions
+//!
+//! A session log is a text file with one KeyEvent per line, holding
+//! the event time in seconds, the event type, the key code, the device
+//! and the key name:
+//!
+//! ```text
+//! # modelm session
+//! 1484092523.418204000 KeyDown 30 0 A
+//! 1484092523.502187000 KeyUp 30 0 A
+//! ```
+//!
+//! The key name is optional, without it the key is looked up from the
+//! key code of the platform replaying the log. Empty lines and lines
+//! starting with `#` are ignored.
+//!
+//! Session logs are written by `Keyboard::record` and played back with
+//! a `ReplaySource`.
+
+use ffi::types::{EventType, Key, KeyEvent};
+use input::InputSource;
+use std::io::prelude::*;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use ::errors::KeyboardError;
+
+pub static HEADER: &'static str = "# modelm session";
+
+/// Appends an event to a session log
+pub fn write_event<W: Write>(writer: &mut W, event: &KeyEvent) -> Result<(), KeyboardError>
+{
+    try!(writeln!(writer, "{}.{:09} {:?} {} {} {}",
+                  event.time.as_secs(), event.time.subsec_nanos(),
+                  event.etype, event.code, event.device, event.key.name()));
+    Ok(())
+}
+
+fn parse_event_type(etype: &str) -> Option<EventType>
+{
+    match etype {
+        "KeyDown" => Some(EventType::KeyDown),
+        "KeyUp" => Some(EventType::KeyUp),
+        "KeyRepeat" => Some(EventType::KeyRepeat),
+        "FlagsChanged" => Some(EventType::FlagsChanged),
+        _ => None,
+    }
+}
+
+fn parse_time(time: &str) -> Option<Duration>
+{
+    let mut parts = time.splitn(2, '.');
+    let secs = parts.next().and_then(|secs| secs.parse().ok());
+    let nanos = match parts.next() {
+        Some(frac) if frac.len() <= 9 => frac.parse::<u32>().ok()
+            .map(|nanos| nanos * 10u32.pow(9 - frac.len() as u32)),
+        Some(_) => None,
+        None => Some(0),
+    };
+    match (secs, nanos) {
+        (Some(secs), Some(nanos)) => Some(Duration::new(secs, nanos)),
+        _ => None,
+    }
+}
+
+/// Parses one line of a session log
+pub fn parse_event(line: &str) -> Result<KeyEvent, KeyboardError>
+{
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let error = || KeyboardError::Config(format!("Unable to parse event: {:?}", line));
+
+    if fields.len() != 4 && fields.len() != 5 {
+        return Err(error())
+    }
+
+    let code = try!(fields[2].parse().map_err(|_| error()));
+    let key = match fields.get(4) {
+        Some(name) => try!(name.parse()),
+        None => Key::from_native(code),
+    };
+
+    Ok(KeyEvent {
+        time: try!(parse_time(fields[0]).ok_or_else(&error)),
+        etype: try!(parse_event_type(fields[1]).ok_or_else(&error)),
+        code: code,
+        key: key,
+        device: try!(fields[3].parse().map_err(|_| error())),
+    })
+}
+
+/// Parses one line of a session log, None for blank and comment lines
+fn parse_line(line: &str) -> Result<Option<KeyEvent>, KeyboardError>
+{
+    let line = line.trim();
+    match line.is_empty() || line.starts_with('#') {
+        true => Ok(None),
+        false => parse_event(line).map(Some),
+    }
+}
+
+/// Reads all events from a session log
+pub fn read_events<R: BufRead>(reader: R) -> Result<Vec<KeyEvent>, KeyboardError>
+{
+    let mut events = vec![];
+    for line in reader.lines() {
+        if let Some(event) = try!(parse_line(&*try!(line))) {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+
+/// Plays back a session log with its original timing, unless untimed
+pub struct ReplaySource<R> {
+    reader: R,
+    timed: bool,
+}
+
+impl<R: BufRead + Send> ReplaySource<R> {
+    pub fn new(reader: R) -> ReplaySource<R>
+    {
+        ReplaySource { reader: reader, timed: true }
+    }
+
+    /// Sends the events as fast as possible, for consumers that only
+    /// look at their timestamps
+    pub fn untimed(mut self) -> ReplaySource<R>
+    {
+        self.timed = false;
+        self
+    }
+}
+
+impl<R: BufRead + Send> InputSource for ReplaySource<R> {
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+        let mut previous: Option<Duration> = None;
+        for line in (&mut self.reader).lines() {
+            let event = match try!(parse_line(&*try!(line))) {
+                Some(event) => event,
+                None => continue,
+            };
+            if let (true, Some(previous)) = (self.timed, previous) {
+                thread::sleep(event.time.checked_sub(previous).unwrap_or(Duration::new(0, 0)));
+            }
+            previous = Some(event.time);
+            if tx.send(event).is_err() {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{read_events, write_event, HEADER};
+    use ffi::types::{EventType, Key, KeyEvent};
+    use std::time::Duration;
+
+    #[test]
+    fn session_roundtrip_OK() -> () {
+        let mut log = format!("{}\n", HEADER).into_bytes();
+        write_event(&mut log, &KeyEvent {
+            etype: EventType::KeyDown, code: 30, key: Key::A, device: 1, time: Duration::new(12, 5000),
+        }).unwrap();
+        write_event(&mut log, &KeyEvent {
+            etype: EventType::KeyUp, code: 30, key: Key::A, device: 1, time: Duration::new(12, 80005000),
+        }).unwrap();
+
+        let events = read_events(&log[..]).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].code, 30);
+        assert_eq!(events[1].key, Key::A);
+        assert_eq!(events[1].device, 1);
+        assert_eq!(events[1].time, Duration::new(12, 80005000));
+    }
+
+    #[test]
+    fn session_short_fraction_OK() -> () {
+        let events = read_events(&b"1.5 KeyDown 49 0 Space"[..]).unwrap();
+        assert_eq!(events[0].time, Duration::new(1, 500000000));
+        assert_eq!(events[0].key, Key::Space);
+    }
+
+    #[test]
+    fn session_invalid_line_ERR() -> () {
+        assert!(read_events(&b"1.5 KeyPress 57 0"[..]).is_err());
+    }
+}
@@ -3,65 +3,136 @@
 //! This module contains a SwitchSound struct that holds options for
 //! keyboard switches in relation to sounds.
 
-use rand;
-use ears::AudioController;
-use ears::Sound;
-use ffi::types::{KeyEvent, KeyCode, EventType};
+use dynamics::Stroke;
+use ffi::types::{KeyEvent, Key, KeyCode, KeyGroup, EventType};
 use regex::Regex;
-use rand::distributions::IndependentSample;
-use rand::distributions::Range;
 use keyboard::KeyboardOptions;
+use rand::StdRng;
+use rand::distributions::{IndependentSample, Range};
+use selection::{self, Selector, Strategy};
+use voice::{Sample, VoicePool};
+use std::collections::HashSet;
 use std::path::Path;
 use yaml_rust;
 use yaml_rust::Yaml;
+use std::str::FromStr;
 use ::errors::KeyboardError;
 
-#[cfg(target_os = "macos")] const MIDDLE: f32 = 25.0;
-#[cfg(target_os = "linux")] const MIDDLE: f32 = 50.0;
+/// How far left and right of the center the outermost keys of the
+/// layout are placed
+const PAN_SCALE: f32 = 0.25;
+/// How far in front of the origin the front row is placed
+const KEYBOARD_DISTANCE: f32 = 1.0;
+/// How much farther away the back row is than the front row
+const ROW_DEPTH: f32 = 0.3;
+/// How much higher the back row is than the front row
+const ROW_RISE: f32 = 0.1;
 
 
 pub struct SwitchSound {
-    sound: Sound,
-    name: String,
+    sample: Sample,
+    /// How often the sound is chosen relative to the others
+    pub weight: f32,
+    /// The velocities of strokes the sound plays for
+    pub velocity: (f32, f32),
+}
+
+
+/// What a switch plays while its key is held down and auto-repeating
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepeatMode {
+    /// Play nothing
+    Silent,
+    /// Replay the keydown sounds
+    Keydown,
+    /// Play the keyrepeat sounds
+    Keyrepeat,
 }
 
 
 pub struct Switch {
     pub sounds_keydown: Vec<SwitchSound>,
     pub sounds_keyup: Vec<SwitchSound>,
-    pub keycode_regex: Regex,
+    pub sounds_keyrepeat: Vec<SwitchSound>,
+    pub repeat_mode: RepeatMode,
+    strategy: Strategy,
+    seed: Option<usize>,
+    selector_keydown: Selector,
+    selector_keyup: Selector,
+    selector_keyrepeat: Selector,
+    rng: StdRng,
+    /// Every play changes the pitch by a random factor in this range
+    pub pitch_range: (f32, f32),
+    /// Every play scales the volume by a random factor in this range
+    pub gain_range: (f32, f32),
+    /// Matched against the platform key code of events
+    pub keycode_regex: Option<Regex>,
+    /// Logical keys the switch handles
+    pub keys: HashSet<Key>,
+    /// Inclusive ranges of platform key codes the switch handles
+    pub codes: Vec<(KeyCode, KeyCode)>,
+    /// Groups of keys the switch handles
+    pub groups: Vec<KeyGroup>,
     pub position: [f32; 3],
 }
 
 
 impl SwitchSound {
-    fn from_path(path: &Path) -> Result<SwitchSound, KeyboardError>
+    fn from_path(path: &Path, voices: &mut VoicePool) -> Result<SwitchSound, KeyboardError>
     {
-        let path_str = path.to_str().ok_or(format!("Unable to load sound: {:?}", path))?;
-        let sound = Sound::new(path_str)?;
-
-        let name = try!(path.file_name()
-            .ok_or(format!("Unable to parse filename: {:?}", path))
-            .and_then(|n| Ok(n.to_string_lossy().to_string())));
-
         Ok(SwitchSound {
-            name: name,
-            sound: sound,
+            sample: try!(voices.load(path)),
+            weight: 1.0,
+            velocity: (0.0, 1.0),
         })
     }
+
+    fn with_weight(mut self, weight: f32) -> SwitchSound
+    {
+        self.weight = weight;
+        self
+    }
+
+    fn with_velocity(mut self, velocity: (f32, f32)) -> SwitchSound
+    {
+        self.velocity = velocity;
+        self
+    }
+
+    /// The weight of the sound for a stroke of `velocity`, zero if it
+    /// doesn't play for it
+    fn weight_at(&self, velocity: f32) -> f32 {
+        match velocity >= self.velocity.0 && velocity <= self.velocity.1 {
+            true => self.weight,
+            false => 0.0,
+        }
+    }
+}
+
+impl FromStr for RepeatMode {
+    type Err = KeyboardError;
+
+    fn from_str(s: &str) -> Result<RepeatMode, KeyboardError> {
+        match s {
+            "silent" => Ok(RepeatMode::Silent),
+            "keydown" => Ok(RepeatMode::Keydown),
+            "keyrepeat" => Ok(RepeatMode::Keyrepeat),
+            _ => Err(KeyboardError::Config(format!(
+                "keyrepeat must be one of silent, keydown or keyrepeat, not {}", s))),
+        }
+    }
 }
 
-macro_rules! play_random_sound {
-    ($sounds: expr, $position: expr, $options: expr) => {
+macro_rules! play_sound {
+    ($sounds: expr, $selector: expr, $voices: expr, $position: expr, $pitch: expr,
+     $volume: expr, $velocity: expr) => {
         {
-            if $sounds.len() > 0 {
-                let range = Range::new(0, $sounds.len());
-                let idx = range.ind_sample(&mut rand::thread_rng());
-                let sound = &mut $sounds[idx];
-                sound.sound.set_position($position);
-                sound.sound.set_volume($options.volume);
-                debug!("Playing {}", sound.name);
-                sound.sound.play();
+            let (sounds, selector) = (&$sounds, &mut $selector);
+            // fall back to any sound if none plays for the velocity
+            let idx = selector.select(sounds.len(), |i| sounds[i].weight_at($velocity))
+                .or_else(|| selector.select(sounds.len(), |i| sounds[i].weight));
+            if let Some(idx) = idx {
+                $voices.play(&sounds[idx].sample, $position, $pitch, $volume);
             }
         }
     };
@@ -74,66 +145,249 @@ impl Switch {
         Switch {
             sounds_keydown: vec![],
             sounds_keyup: vec![],
-            keycode_regex: Regex::new(".*").unwrap(),
+            sounds_keyrepeat: vec![],
+            repeat_mode: RepeatMode::Silent,
+            strategy: Strategy::Uniform,
+            seed: None,
+            selector_keydown: Selector::new(Strategy::Uniform),
+            selector_keyup: Selector::new(Strategy::Uniform),
+            selector_keyrepeat: Selector::new(Strategy::Uniform),
+            rng: selection::rng(None),
+            pitch_range: (1.0, 1.0),
+            gain_range: (1.0, 1.0),
+            keycode_regex: None,
+            keys: HashSet::new(),
+            codes: vec![],
+            groups: vec![],
             position: [0.0, 0.0, 1.0],
         }
     }
 
     pub fn with_keycode_regex(mut self, regex: Regex) -> Switch
     {
-        self.keycode_regex = regex;
+        self.keycode_regex = Some(regex);
+        self
+    }
+
+    pub fn with_keys(mut self, keys: Vec<Key>) -> Switch
+    {
+        self.keys.extend(keys);
+        self
+    }
+
+    pub fn with_codes(mut self, codes: Vec<(KeyCode, KeyCode)>) -> Switch
+    {
+        self.codes.extend(codes);
         self
     }
 
-    pub fn load_sound_keydown(mut self, path: &Path) -> Result<Switch, KeyboardError>
+    pub fn with_groups(mut self, groups: Vec<KeyGroup>) -> Switch
     {
-        self.sounds_keydown.push(try!(SwitchSound::from_path(path)));
+        self.groups.extend(groups);
+        self
+    }
+
+    pub fn with_repeat_mode(mut self, repeat_mode: RepeatMode) -> Switch
+    {
+        self.repeat_mode = repeat_mode;
+        self
+    }
+
+    /// Sets how samples are chosen, with the same choices every time
+    /// if a seed is given
+    pub fn with_selection(mut self, strategy: Strategy, seed: Option<usize>) -> Switch
+    {
+        self.strategy = strategy;
+        if seed.is_some() {
+            self.seed = seed;
+        }
+        self.reseed();
+        self
+    }
+
+    /// Makes the choice of samples and their pitch and gain the same
+    /// every time
+    pub fn with_seed(mut self, seed: usize) -> Switch
+    {
+        self.seed = Some(seed);
+        self.reseed();
+        self
+    }
+
+    pub fn with_pitch_range(mut self, pitch_range: (f32, f32)) -> Switch
+    {
+        self.pitch_range = pitch_range;
+        self
+    }
+
+    pub fn with_gain_range(mut self, gain_range: (f32, f32)) -> Switch
+    {
+        self.gain_range = gain_range;
+        self
+    }
+
+    /// Recreates the selectors and the random number generator after
+    /// the strategy or the seed changed
+    fn reseed(&mut self) {
+        let (strategy, seed) = (self.strategy, self.seed);
+        let selector = |offset| match seed {
+            Some(seed) => Selector::with_seed(strategy, seed.wrapping_add(offset)),
+            None => Selector::new(strategy),
+        };
+        self.selector_keydown = selector(0);
+        self.selector_keyup = selector(1);
+        self.selector_keyrepeat = selector(2);
+        self.rng = selection::rng(seed.map(|seed| seed.wrapping_add(3)));
+    }
+
+    /// Returns the pitch and the gain of the next sound
+    fn humanize(&mut self) -> (f32, f32) {
+        (jitter(&mut self.rng, self.pitch_range), jitter(&mut self.rng, self.gain_range))
+    }
+
+    pub fn load_sound_keydown(mut self, path: &Path, voices: &mut VoicePool)
+                            -> Result<Switch, KeyboardError>
+    {
+        self.sounds_keydown.push(try!(SwitchSound::from_path(path, voices)));
         Ok(self)
     }
 
-    pub fn load_sound_keyup(mut self, path: &Path) -> Result<Switch, KeyboardError>
+    pub fn load_sound_keyup(mut self, path: &Path, voices: &mut VoicePool)
+                            -> Result<Switch, KeyboardError>
     {
-        self.sounds_keyup.push(try!(SwitchSound::from_path(path)));
+        self.sounds_keyup.push(try!(SwitchSound::from_path(path, voices)));
         Ok(self)
     }
 
-    pub fn handle_event(&mut self, event: KeyEvent, options: &KeyboardOptions) {
-        let position = - (MIDDLE - event.code as f32) * options.x_scale / 300.0;
+    pub fn load_sound_keyrepeat(mut self, path: &Path, voices: &mut VoicePool)
+                            -> Result<Switch, KeyboardError>
+    {
+        self.sounds_keyrepeat.push(try!(SwitchSound::from_path(path, voices)));
+        Ok(self)
+    }
+
+    pub fn handle_event(&mut self, event: KeyEvent, stroke: Stroke, options: &KeyboardOptions,
+                        voices: &mut VoicePool) {
+        let position = key_position(event.key, options);
+        let (pitch, gain) = self.humanize();
+        let volume = options.volume * gain * stroke.gain;
+        let velocity = stroke.velocity;
         match event.etype {
             EventType::KeyDown => {
-                play_random_sound!(self.sounds_keydown, [position, 0.0, 1.0], options);
+                play_sound!(self.sounds_keydown, self.selector_keydown, voices, position, pitch, volume, velocity);
             },
             EventType::KeyUp => {
-                play_random_sound!(self.sounds_keyup, [position, 0.0, 1.0], options);
+                play_sound!(self.sounds_keyup, self.selector_keyup, voices, position, pitch, volume, velocity);
+            },
+            EventType::KeyRepeat => match self.repeat_mode {
+                RepeatMode::Silent => (),
+                RepeatMode::Keydown => {
+                    play_sound!(self.sounds_keydown, self.selector_keydown, voices, position, pitch, volume, velocity);
+                },
+                RepeatMode::Keyrepeat => {
+                    play_sound!(self.sounds_keyrepeat, self.selector_keyrepeat, voices, position, pitch, volume, velocity);
+                },
             },
             _ => (),
         }
     }
 
-    pub fn handles(&self, code: KeyCode) -> bool {
-        self.keycode_regex.is_match(&*format!("{}", code))
+    /// Returns true if the switch handles the key of the event, a
+    /// switch without keys, codes, groups or keycode_regex handles
+    /// every key
+    pub fn handles(&self, event: &KeyEvent) -> bool {
+        self.handles_key(event.code, event.key)
     }
 
-    pub fn from_yaml(yaml: &yaml_rust::Yaml) -> Result<Switch, KeyboardError>
+    /// Returns true if the switch handles `key` with platform key code
+    /// `code`
+    pub fn handles_key(&self, code: KeyCode, key: Key) -> bool {
+        if self.keycode_regex.is_none() && self.keys.is_empty()
+            && self.codes.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+
+        self.keys.contains(&key)
+            || self.codes.iter().any(|&(from, to)| from <= code && code <= to)
+            || self.groups.iter().any(|group| group.contains(key))
+            || self.keycode_regex.as_ref()
+                .map_or(false, |regex| regex.is_match(&*format!("{}", code)))
+    }
+
+    /// Parses a switch config, loading its sounds into `voices`
+    pub fn from_yaml(yaml: &yaml_rust::Yaml, voices: &mut VoicePool) -> Result<Switch, KeyboardError>
     {
         let hash = try_yaml!(*yaml, Yaml::Hash, "switch must be a Hash [switch]");
 
-        let regex_str = try_yaml!(yaml["keycode_regex"], Yaml::String,
-                                  "config must have Hash [switch.keycode_regex]");
+        let has_keycode_regex = hash.contains_key(&Yaml::String("keycode_regex".into()));
+        let has_keys = hash.contains_key(&Yaml::String("keys".into()));
+        let has_codes = hash.contains_key(&Yaml::String("codes".into()));
+        let has_groups = hash.contains_key(&Yaml::String("groups".into()));
+        if !has_keycode_regex && !has_keys && !has_codes && !has_groups {
+            return Err(KeyboardError::Config(
+                "config must have [switch.keys], [switch.codes], [switch.groups] \
+                 or [switch.keycode_regex]".into()));
+        }
+
+        let mut switch = Switch::new();
+
+        if has_keycode_regex {
+            let regex_str = try_yaml!(yaml["keycode_regex"], Yaml::String,
+                                      "config must have String [switch.keycode_regex]");
+
+            info!("Parsed keycode_regex : {}", regex_str);
+            switch = switch.with_keycode_regex(try!(Regex::new(&*regex_str)));
+        }
+
+        if has_keys {
+            let key_names = try_yaml!(yaml["keys"], Yaml::Array,
+                                      "config must have Array [switch.keys]");
+
+            let mut keys = vec![];
+            for key_name in key_names {
+                let name = try!(key_name.as_str()
+                    .ok_or(format!("Unable to parse key: {:?}", key_name)));
+                keys.push(try!(name.parse()));
+            }
+            info!("Parsed keys: {:?}", keys);
+            switch = switch.with_keys(keys);
+        }
+
+        if has_codes {
+            let code_ranges = try_yaml!(yaml["codes"], Yaml::Array,
+                                        "config must have Array [switch.codes]");
+
+            let mut codes = vec![];
+            for code_range in code_ranges {
+                codes.push(try!(parse_code_range(code_range)));
+            }
+            info!("Parsed codes: {:?}", codes);
+            switch = switch.with_codes(codes);
+        }
 
-        info!("Parsed keycode_regex : {}", regex_str);
+        if has_groups {
+            let group_names = try_yaml!(yaml["groups"], Yaml::Array,
+                                        "config must have Array [switch.groups]");
 
-        let mut switch = Switch::new().with_keycode_regex(try!(Regex::new(&*regex_str)));
+            let mut groups = vec![];
+            for group_name in group_names {
+                let name = try!(group_name.as_str()
+                    .ok_or(format!("Unable to parse group: {:?}", group_name)));
+                groups.push(try!(name.parse()));
+            }
+            info!("Parsed groups: {:?}", groups);
+            switch = switch.with_groups(groups);
+        }
 
         if hash.contains_key(&Yaml::String("keydown_paths".into())){
             let keydown_paths = try_yaml!(yaml["keydown_paths"], Yaml::Array,
                                           "config must have Array [switch.keydown_paths]");
 
             for keydown_path in keydown_paths {
-                let path = try!(keydown_path.as_str()
-                    .ok_or(format!("Unable to parse path: {:?}", keydown_path)));
-                info!("Parsed keydown path: {}", path);
-                switch = try!(switch.load_sound_keydown(&Path::new(path)));
+                let (path, weight, velocity) = try!(parse_sound_path(keydown_path));
+                info!("Parsed keydown path: {} (weight {}, velocity {:?})", path, weight, velocity);
+                let sound = try!(SwitchSound::from_path(&Path::new(path), voices));
+                switch.sounds_keydown.push(sound.with_weight(weight).with_velocity(velocity));
             }
         }
 
@@ -142,12 +396,238 @@ impl Switch {
                                         "config must have Array [switch.keyup_paths]");
 
             for keyup_path in keyup_paths {
-                let path = try!(keyup_path.as_str()
-                    .ok_or(format!("Unable to parse path: {:?}", keyup_path)));
-                info!("Parsed keyup path: {}", path);
-                switch = try!(switch.load_sound_keyup(&Path::new(path)));
+                let (path, weight, velocity) = try!(parse_sound_path(keyup_path));
+                info!("Parsed keyup path: {} (weight {}, velocity {:?})", path, weight, velocity);
+                let sound = try!(SwitchSound::from_path(&Path::new(path), voices));
+                switch.sounds_keyup.push(sound.with_weight(weight).with_velocity(velocity));
+            }
+        }
+
+        if hash.contains_key(&Yaml::String("keyrepeat_paths".into())){
+            let keyrepeat_paths = try_yaml!(yaml["keyrepeat_paths"], Yaml::Array,
+                                            "config must have Array [switch.keyrepeat_paths]");
+
+            for keyrepeat_path in keyrepeat_paths {
+                let (path, weight, velocity) = try!(parse_sound_path(keyrepeat_path));
+                info!("Parsed keyrepeat path: {} (weight {}, velocity {:?})", path, weight, velocity);
+                let sound = try!(SwitchSound::from_path(&Path::new(path), voices));
+                switch.sounds_keyrepeat.push(sound.with_weight(weight).with_velocity(velocity));
+            }
+            switch = switch.with_repeat_mode(RepeatMode::Keyrepeat);
+        }
+
+        if hash.contains_key(&Yaml::String("selection".into())){
+            let strategy = try_yaml!(yaml["selection"], Yaml::String,
+                                     "config must have String [switch.selection]");
+            info!("Parsed selection: {}", strategy);
+            switch = switch.with_selection(try!(strategy.parse()), None);
+        }
+
+        if hash.contains_key(&Yaml::String("seed".into())){
+            let seed = match yaml["seed"] {
+                Yaml::Integer(seed) if seed >= 0 => seed as usize,
+                _ => return Err(KeyboardError::Config(
+                    "config must have a positive Integer [switch.seed]".into())),
+            };
+            info!("Parsed seed: {}", seed);
+            switch = switch.with_seed(seed);
+        }
+
+        if hash.contains_key(&Yaml::String("pitch_range".into())){
+            let pitch_range = try!(parse_range(&yaml["pitch_range"], "pitch_range"));
+            if pitch_range.0 <= 0.0 {
+                return Err(KeyboardError::Config("[switch.pitch_range] must be above 0".into()));
             }
+            info!("Parsed pitch_range: {:?}", pitch_range);
+            switch = switch.with_pitch_range(pitch_range);
+        }
+
+        if hash.contains_key(&Yaml::String("gain_range".into())){
+            let gain_range = try!(parse_range(&yaml["gain_range"], "gain_range"));
+            if gain_range.0 < 0.0 {
+                return Err(KeyboardError::Config("[switch.gain_range] must not be negative".into()));
+            }
+            info!("Parsed gain_range: {:?}", gain_range);
+            switch = switch.with_gain_range(gain_range);
+        }
+
+        if hash.contains_key(&Yaml::String("keyrepeat".into())){
+            let repeat_mode = try_yaml!(yaml["keyrepeat"], Yaml::String,
+                                        "config must have String [switch.keyrepeat]");
+            info!("Parsed keyrepeat: {}", repeat_mode);
+            switch = switch.with_repeat_mode(try!(repeat_mode.parse()));
         }
         Ok(switch)
     }
 }
+
+
+/// Places `key` on a keyboard lying in front of the origin along -z,
+/// where OpenAL's listener faces by default. Rows farther back are
+/// farther away and higher up.
+fn key_position(key: Key, options: &KeyboardOptions) -> [f32; 3] {
+    let depth = options.layout.depth(key);
+    [options.layout.pan(key) * options.x_scale * PAN_SCALE,
+     (depth - 1.0) * ROW_RISE,
+     - KEYBOARD_DISTANCE - depth * ROW_DEPTH]
+}
+
+
+/// Returns a random number in `range`
+fn jitter(rng: &mut StdRng, range: (f32, f32)) -> f32 {
+    match range {
+        (low, high) if low < high => Range::new(low, high).ind_sample(rng),
+        (low, _) => low,
+    }
+}
+
+
+/// Parses a range of decimals like `[0.95, 1.05]`
+fn parse_range(yaml: &Yaml, name: &str) -> Result<(f32, f32), KeyboardError> {
+    let error = || KeyboardError::Config(format!(
+        "config must have [low, high] decimals in [switch.{}]", name));
+    let number = |yaml: &Yaml| match *yaml {
+        Yaml::Real(_) => yaml.as_f64().map(|n| n as f32).ok_or_else(&error),
+        Yaml::Integer(n) => Ok(n as f32),
+        _ => Err(error()),
+    };
+
+    let range = match *yaml {
+        Yaml::Array(ref range) if range.len() == 2 => (try!(number(&range[0])), try!(number(&range[1]))),
+        _ => return Err(error()),
+    };
+    match range.0 <= range.1 {
+        true => Ok(range),
+        false => Err(error()),
+    }
+}
+
+
+/// Parses a sound path, either just the path or a Hash with the path,
+/// its weight and the velocities it plays for, e.g.
+/// `{path: down_1.wav, weight: 2, velocity: [0.5, 1]}`
+fn parse_sound_path(yaml: &Yaml) -> Result<(&str, f32, (f32, f32)), KeyboardError> {
+    let error = || KeyboardError::Config(format!("Unable to parse path: {:?}", yaml));
+
+    match *yaml {
+        Yaml::String(ref path) => Ok((path, 1.0, (0.0, 1.0))),
+        Yaml::Hash(_) => {
+            let path = try!(yaml["path"].as_str().ok_or_else(&error));
+            let weight = match yaml["weight"] {
+                Yaml::Real(_) => try!(yaml["weight"].as_f64().ok_or_else(&error)),
+                Yaml::Integer(weight) => weight as f64,
+                Yaml::BadValue => 1.0,
+                _ => return Err(error()),
+            };
+            let velocity = match yaml["velocity"] {
+                Yaml::BadValue => (0.0, 1.0),
+                ref velocity => try!(parse_range(velocity, "velocity")),
+            };
+            Ok((path, weight as f32, velocity))
+        },
+        _ => Err(error()),
+    }
+}
+
+
+/// Parses a key code, e.g. `28`, or an inclusive range of key codes,
+/// e.g. `1-14`
+fn parse_code_range(yaml: &Yaml) -> Result<(KeyCode, KeyCode), KeyboardError> {
+    let error = || KeyboardError::Config(format!("Unable to parse code range: {:?}", yaml));
+    let parse = |code: &str| code.trim().parse::<KeyCode>().map_err(|_| error());
+
+    match *yaml {
+        Yaml::Integer(code) if code >= 0 && code <= KeyCode::max_value() as i64 =>
+            Ok((code as KeyCode, code as KeyCode)),
+        Yaml::String(ref range) => {
+            let mut bounds = range.splitn(2, '-');
+            let from = try!(parse(bounds.next().unwrap_or("")));
+            let to = match bounds.next() {
+                Some(to) => try!(parse(to)),
+                None => from,
+            };
+            if from > to {
+                return Err(error());
+            }
+            Ok((from, to))
+        },
+        _ => Err(error()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{parse_code_range, parse_range, parse_sound_path, RepeatMode, Switch};
+    use audio::NullBackend;
+    use ffi::types::{EventType, Key, KeyEvent};
+    use std::time::Duration;
+    use voice::VoicePool;
+    use yaml_rust::{Yaml, YamlLoader};
+
+    #[test]
+    fn switch_code_range_OK() -> () {
+        assert_eq!(parse_code_range(&Yaml::Integer(28)).unwrap(), (28, 28));
+        assert_eq!(parse_code_range(&Yaml::String("1-14".into())).unwrap(), (1, 14));
+        assert!(parse_code_range(&Yaml::String("14-1".into())).is_err());
+        assert!(parse_code_range(&Yaml::Integer(-1)).is_err());
+    }
+
+    #[test]
+    fn switch_repeat_mode_OK() -> () {
+        assert_eq!("silent".parse::<RepeatMode>().unwrap(), RepeatMode::Silent);
+        assert_eq!("keydown".parse::<RepeatMode>().unwrap(), RepeatMode::Keydown);
+        assert_eq!("keyrepeat".parse::<RepeatMode>().unwrap(), RepeatMode::Keyrepeat);
+    }
+
+    #[test]
+    fn switch_repeat_mode_ERR() -> () {
+        assert!("".parse::<RepeatMode>().is_err());
+        assert!("repeat".parse::<RepeatMode>().is_err());
+    }
+
+    #[test]
+    fn switch_sound_path_OK() -> () {
+        let paths = &YamlLoader::load_from_str("[a.wav, {path: b.wav, weight: 2.5}, {weight: 2}, {path: c.wav, velocity: [0.5, 1]}]")
+            .unwrap()[0];
+        assert_eq!(parse_sound_path(&paths[0]).unwrap(), ("a.wav", 1.0, (0.0, 1.0)));
+        assert_eq!(parse_sound_path(&paths[1]).unwrap(), ("b.wav", 2.5, (0.0, 1.0)));
+        assert!(parse_sound_path(&paths[2]).is_err());
+        assert_eq!(parse_sound_path(&paths[3]).unwrap(), ("c.wav", 1.0, (0.5, 1.0)));
+    }
+
+    #[test]
+    fn switch_humanize_OK() -> () {
+        let ranges = &YamlLoader::load_from_str("[[0.9, 1.1], [1, 1], [1.1, 0.9], [1]]").unwrap()[0];
+        assert_eq!(parse_range(&ranges[0], "pitch_range").unwrap(), (0.9, 1.1));
+        assert_eq!(parse_range(&ranges[1], "pitch_range").unwrap(), (1.0, 1.0));
+        assert!(parse_range(&ranges[2], "pitch_range").is_err());
+        assert!(parse_range(&ranges[3], "pitch_range").is_err());
+
+        let switch = || Switch::new().with_pitch_range((0.9, 1.1)).with_gain_range((0.5, 1.0)).with_seed(7);
+        let (mut a, mut b) = (switch(), switch());
+        for _ in 0..10 {
+            let (pitch, gain) = a.humanize();
+            assert_eq!((pitch, gain), b.humanize());
+            assert!(pitch >= 0.9 && pitch < 1.1 && gain >= 0.5 && gain < 1.0);
+        }
+        assert_eq!(Switch::new().humanize(), (1.0, 1.0));
+    }
+
+    #[test]
+    fn switch_handles_OK() -> () {
+        let yaml = &YamlLoader::load_from_str("{codes: [1-14, 28], groups: [numpad]}").unwrap()[0];
+        let switch = Switch::from_yaml(yaml, &mut VoicePool::new(Box::new(NullBackend::new()))).unwrap();
+        let event = |code, key| KeyEvent {
+            etype: EventType::KeyDown, code: code, key: key, device: 0, time: Duration::new(0, 0)
+        };
+
+        assert!(switch.handles(&event(14, Key::Backspace)));
+        assert!(switch.handles(&event(28, Key::Enter)));
+        assert!(switch.handles(&event(96, Key::KeypadEnter)));
+        assert!(!switch.handles(&event(57, Key::Space)));
+        assert!(Switch::new().handles(&event(57, Key::Space)));
+    }
+}
@@ -0,0 +1,527 @@
+//! Keyboard events from the terminal and standard input
+//!
+//! A TerminalSource puts the controlling terminal in raw mode and turns
+//! what is typed into KeyEvents, so it works without root or access to
+//! /dev/input. A PipeSource passes standard input through to standard
+//! output and turns every character into KeyEvents.
+//!
+//! Both only see characters, not key releases, so the key up for each
+//! key is synthesized KEYUP_DELAY_MS after the key down. Piped data can
+//! arrive thousands of characters at once, so a PipeSource presses them
+//! one at a time like a fast typist and drops what it can't keep up
+//! with.
+
+use ffi;
+use ffi::types::{EventType, Key, KeyCode, KeyEvent};
+use input::{InputSource, StopHandle};
+use libc;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io::Write;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use std::{io, mem, thread};
+use ::errors::KeyboardError;
+
+/// How long a key is held before its synthesized key up
+const KEYUP_DELAY_MS: u64 = 60;
+
+/// Time between the presses of piped characters
+const PIPE_PRESS_INTERVAL_MS: u64 = 30;
+
+/// Longest wait for input before checking whether to stop
+const STOP_POLL_MS: u64 = 100;
+
+/// Piped characters waiting to be pressed at most, more are dropped so
+/// the clicks keep up with the output
+const MAX_PIPE_BACKLOG: usize = 64;
+
+/// Typed input, as decoded from the bytes read from the terminal
+#[derive(Debug, PartialEq)]
+enum Typed {
+    /// A character, including control characters like tab or escape
+    Char(char),
+    /// A key sent as an escape sequence, e.g. the arrow keys
+    Key(Key),
+    /// ^C or ^D, ends the session
+    Exit,
+}
+
+/// Returns the key for the final byte of an `ESC [` or `ESC O`
+/// sequence, with the numeric parameter of `ESC [ n ~` sequences
+fn escape_key(param: &[u8], last: u8) -> Option<Key> {
+    match (param, last) {
+        (_, b'A') => Some(Key::Up),
+        (_, b'B') => Some(Key::Down),
+        (_, b'C') => Some(Key::Right),
+        (_, b'D') => Some(Key::Left),
+        (_, b'H') => Some(Key::Home),
+        (_, b'F') => Some(Key::End),
+        (_, b'P') => Some(Key::F1),
+        (_, b'Q') => Some(Key::F2),
+        (_, b'R') => Some(Key::F3),
+        (_, b'S') => Some(Key::F4),
+        (b"1", b'~') | (b"7", b'~') => Some(Key::Home),
+        (b"4", b'~') | (b"8", b'~') => Some(Key::End),
+        (b"2", b'~') => Some(Key::Insert),
+        (b"3", b'~') => Some(Key::Delete),
+        (b"5", b'~') => Some(Key::PageUp),
+        (b"6", b'~') => Some(Key::PageDown),
+        (b"15", b'~') => Some(Key::F5),
+        (b"17", b'~') => Some(Key::F6),
+        (b"18", b'~') => Some(Key::F7),
+        (b"19", b'~') => Some(Key::F8),
+        (b"20", b'~') => Some(Key::F9),
+        (b"21", b'~') => Some(Key::F10),
+        (b"23", b'~') => Some(Key::F11),
+        (b"24", b'~') => Some(Key::F12),
+        _ => None,
+    }
+}
+
+/// Decodes the bytes of one read from the terminal. Escape sequences
+/// are expected to arrive within a single read, as terminals write
+/// them at once.
+fn decode(bytes: &[u8]) -> Vec<Typed> {
+    let mut typed = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            0x03 | 0x04 => typed.push(Typed::Exit),
+            0x1b if i + 1 < bytes.len() && (bytes[i + 1] == b'[' || bytes[i + 1] == b'O') => {
+                // parameters are digits and `;`, up to the final byte
+                let start = i + 2;
+                let mut end = start;
+                while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b';') {
+                    end += 1;
+                }
+                if end == bytes.len() {
+                    break
+                }
+                let param = bytes[start..end].split(|&b| b == b';').next().unwrap_or(b"");
+                if let Some(key) = escape_key(param, bytes[end]) {
+                    typed.push(Typed::Key(key));
+                }
+                i = end;
+            },
+            // alt + key arrives as escape followed by the key
+            0x1b if i + 1 < bytes.len() && bytes[i + 1] != 0x1b => (),
+            // ^A to ^Z, except the ones that are keys of their own
+            byte @ 0x01..=0x1a if byte != 0x08 && byte != b'\t' && byte != b'\n' && byte != b'\r' =>
+                typed.push(Typed::Char((byte - 1 + b'a') as char)),
+            byte if byte < 0x80 => typed.push(Typed::Char(byte as char)),
+            // only count the first byte of UTF-8 characters
+            byte if byte >= 0xc0 => typed.push(Typed::Char('?')),
+            _ => (),
+        }
+        i += 1;
+    }
+    typed
+}
+
+
+/// The characters of piped data that press keys. Control bytes are data
+/// in a pipe, not ^letters, so only newlines and tabs are kept.
+fn pipe_chars(bytes: &[u8]) -> Vec<char> {
+    let bytes: Vec<u8> = bytes.iter()
+        .cloned()
+        .filter(|&byte| byte > 0x1a || byte == b'\n' || byte == b'\t')
+        .collect();
+    decode(&bytes).into_iter()
+        .filter_map(|typed| match typed {
+            Typed::Char(c) => Some(c),
+            // escape sequences are most likely colors
+            Typed::Key(_) | Typed::Exit => None,
+        })
+        .collect()
+}
+
+
+/// Waits up to `timeout` for input on `fd`, returns whether there is
+/// any
+fn poll(fd: libc::c_int, timeout: Option<Duration>) -> Result<bool, io::Error> {
+    let timeout = timeout.map_or(-1, |timeout| {
+        (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as libc::c_int
+    });
+    let mut fds = libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut fds, 1, timeout) } {
+        n if n < 0 => {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err),
+            }
+        },
+        n => Ok(n > 0),
+    }
+}
+
+/// Caps the time to wait for input so a stop is noticed
+fn stop_timeout(timeout: Option<Duration>) -> Option<Duration> {
+    let stop = Duration::from_millis(STOP_POLL_MS);
+    Some(timeout.map_or(stop, |timeout| timeout.min(stop)))
+}
+
+fn read(fd: libc::c_int, buf: &mut [u8]) -> Result<usize, io::Error> {
+    match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+
+/// The controlling terminal, in raw mode until dropped
+struct Terminal {
+    fd: libc::c_int,
+    original: libc::termios,
+}
+
+impl Terminal {
+    fn open() -> Result<Terminal, io::Error> {
+        let path = CString::new("/dev/tty").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut original) < 0 {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err)
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(fd, libc::TCSANOW, &raw) < 0 {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err)
+            }
+            Ok(Terminal { fd: fd, original: original })
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        unsafe { libc::write(self.fd, bytes.as_ptr() as *const libc::c_void, bytes.len()); }
+    }
+
+    /// Shows what was typed, as raw mode turns off the terminal's echo
+    fn echo(&self, c: char) {
+        match c {
+            '\r' | '\n' => self.write(b"\r\n"),
+            '\x08' | '\x7f' => self.write(b"\x08 \x08"),
+            c if !c.is_control() => self.write(c.to_string().as_bytes()),
+            _ => (),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+            libc::close(self.fd);
+        }
+    }
+}
+
+
+/// Sends key downs for typed keys and their synthesized key ups. The
+/// methods fail when the channel is closed.
+struct KeyPresses {
+    /// Key ups waiting for their delay to pass, in order
+    pending: VecDeque<(Instant, KeyEvent)>,
+}
+
+impl KeyPresses {
+    fn new() -> KeyPresses {
+        KeyPresses { pending: VecDeque::new() }
+    }
+
+    /// How long until the next key up is due, None if none is pending
+    fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.pending.front().map(|&(at, _)| match at > now {
+            true => at - now,
+            false => Duration::new(0, 0),
+        })
+    }
+
+    /// Sends the pending key up for `code` right away, so the key can
+    /// be pressed again
+    fn release_now(&mut self, code: KeyCode, tx: &Sender<KeyEvent>) -> Result<(), ()> {
+        match self.pending.iter().position(|&(_, ref event)| event.code == code) {
+            Some(i) => {
+                let (_, event) = self.pending.remove(i).unwrap();
+                tx.send(KeyEvent { time: ffi::now(), .. event }).map_err(|_| ())
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the key downs for a typed key and schedules the key ups
+    fn press(&mut self, key: Key, shift: bool, tx: &Sender<KeyEvent>) -> Result<(), ()> {
+        let mut keys = vec![(key, EventType::KeyDown, EventType::KeyUp)];
+        if shift {
+            keys.insert(0, (Key::LeftShift, EventType::FlagsChanged, EventType::FlagsChanged));
+        }
+
+        let release_at = Instant::now() + Duration::from_millis(KEYUP_DELAY_MS);
+        for (key, down, up) in keys {
+            let code = match key.native_code() {
+                Some(code) => code,
+                None => {
+                    debug!("No key code for {:?}", key);
+                    continue
+                },
+            };
+            try!(self.release_now(code, tx));
+            let event = |etype| KeyEvent { etype: etype, code: code, key: key, device: 0, time: ffi::now() };
+            try!(tx.send(event(down)).map_err(|_| ()));
+            self.pending.push_back((release_at, event(up)));
+        }
+        Ok(())
+    }
+
+    /// Presses the key that types `c`, if there is one
+    fn press_char(&mut self, c: char, tx: &Sender<KeyEvent>) -> Result<(), ()> {
+        match Key::from_char(c) {
+            Some((key, shift)) => self.press(key, shift, tx),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the key ups whose delay has passed
+    fn release_due(&mut self, tx: &Sender<KeyEvent>) -> Result<(), ()> {
+        let now = Instant::now();
+        while self.pending.front().map_or(false, |&(at, _)| at <= now) {
+            let (_, event) = self.pending.pop_front().unwrap();
+            try!(tx.send(KeyEvent { time: ffi::now(), .. event }).map_err(|_| ()));
+        }
+        Ok(())
+    }
+
+    /// Sends all pending key ups right away
+    fn release_all(&mut self, tx: &Sender<KeyEvent>) -> Result<(), ()> {
+        for (_, event) in self.pending.drain(..) {
+            try!(tx.send(KeyEvent { time: ffi::now(), .. event }).map_err(|_| ()));
+        }
+        Ok(())
+    }
+}
+
+
+/// Reads keys typed in the terminal, no root needed
+pub struct TerminalSource {
+    keys: KeyPresses,
+    stop: StopHandle,
+}
+
+impl TerminalSource {
+    pub fn new() -> TerminalSource {
+        TerminalSource { keys: KeyPresses::new(), stop: StopHandle::new() }
+    }
+}
+
+impl InputSource for TerminalSource {
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+        let terminal = try!(Terminal::open().map_err(|err| {
+            KeyboardError::Listener(format!("Unable to use the terminal: {}", err))
+        }));
+        terminal.write(b"Type away, press ^C or ^D to exit.\r\n");
+
+        let mut buf = [0u8; 64];
+        loop {
+            if self.stop.is_stopped() || self.keys.release_due(&tx).is_err() {
+                return Ok(())
+            }
+            if !try!(poll(terminal.fd, stop_timeout(self.keys.timeout()))) {
+                continue
+            }
+
+            let len = try!(read(terminal.fd, &mut buf));
+            if len == 0 {
+                return Ok(())
+            }
+
+            for typed in decode(&buf[..len]) {
+                let sent = match typed {
+                    Typed::Exit => {
+                        terminal.write(b"\r\n");
+                        return Ok(())
+                    },
+                    Typed::Key(key) => self.keys.press(key, false, &tx),
+                    Typed::Char(c) => {
+                        terminal.echo(c);
+                        self.keys.press_char(c, &tx)
+                    },
+                };
+                if sent.is_err() {
+                    return Ok(())
+                }
+            }
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn stop_handle(&self) -> Option<StopHandle> {
+        Some(self.stop.clone())
+    }
+}
+
+
+/// Passes standard input through to standard output unchanged and
+/// presses a key for every character, e.g. `tail -f log | modelm --pipe`
+pub struct PipeSource {
+    keys: KeyPresses,
+    /// Characters read but not pressed yet
+    backlog: VecDeque<char>,
+    /// When the next character may be pressed
+    next_press: Instant,
+    stop: StopHandle,
+}
+
+impl PipeSource {
+    pub fn new() -> PipeSource {
+        PipeSource {
+            keys: KeyPresses::new(),
+            backlog: VecDeque::new(),
+            next_press: Instant::now(),
+            stop: StopHandle::new(),
+        }
+    }
+
+    /// Queues characters to be pressed, dropping the ones that don't fit
+    fn queue(&mut self, chars: Vec<char>) {
+        let room = MAX_PIPE_BACKLOG - self.backlog.len();
+        if chars.len() > room {
+            debug!("Dropping {} piped characters", chars.len() - room);
+        }
+        self.backlog.extend(chars.into_iter().take(room));
+    }
+
+    /// Presses the next character if it is due
+    fn press_due(&mut self, tx: &Sender<KeyEvent>) -> Result<(), ()> {
+        let now = Instant::now();
+        if now < self.next_press {
+            return Ok(())
+        }
+        match self.backlog.pop_front() {
+            Some(c) => {
+                self.next_press = now + Duration::from_millis(PIPE_PRESS_INTERVAL_MS);
+                self.keys.press_char(c, tx)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// How long until a key up or press is due, None if none is
+    fn timeout(&self) -> Option<Duration> {
+        let press = match self.backlog.is_empty() {
+            true => None,
+            false => {
+                let now = Instant::now();
+                Some(match self.next_press > now {
+                    true => self.next_press - now,
+                    false => Duration::new(0, 0),
+                })
+            },
+        };
+        match (self.keys.timeout(), press) {
+            (Some(release), Some(press)) => Some(release.min(press)),
+            (release, press) => release.or(press),
+        }
+    }
+}
+
+impl InputSource for PipeSource {
+    fn run(&mut self, tx: Sender<KeyEvent>) -> Result<(), KeyboardError> {
+        let stdout = io::stdout();
+        let mut buf = [0u8; 4096];
+        let mut open = true;
+        loop {
+            if self.stop.is_stopped() || self.keys.release_due(&tx).is_err() || self.press_due(&tx).is_err() {
+                return Ok(())
+            }
+            if !open {
+                // press what is left after the end of the input
+                if self.backlog.is_empty() {
+                    let _ = self.keys.release_all(&tx);
+                    return Ok(())
+                }
+                if let Some(timeout) = self.timeout() {
+                    thread::sleep(timeout);
+                }
+                continue
+            }
+            if !try!(poll(libc::STDIN_FILENO, stop_timeout(self.timeout()))) {
+                continue
+            }
+
+            let len = try!(read(libc::STDIN_FILENO, &mut buf));
+            if len == 0 {
+                open = false;
+                continue
+            }
+
+            let mut stdout = stdout.lock();
+            try!(stdout.write_all(&buf[..len]));
+            try!(stdout.flush());
+            self.queue(pipe_chars(&buf[..len]));
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn stop_handle(&self) -> Option<StopHandle> {
+        Some(self.stop.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{decode, pipe_chars, PipeSource, Typed, MAX_PIPE_BACKLOG};
+    use ffi::types::Key;
+
+    #[test]
+    fn terminal_decode_chars_OK() -> () {
+        assert_eq!(decode(b"aB\r\x7f"), vec![
+            Typed::Char('a'), Typed::Char('B'), Typed::Char('\r'), Typed::Char('\x7f'),
+        ]);
+    }
+
+    #[test]
+    fn terminal_decode_escape_sequences_OK() -> () {
+        assert_eq!(decode(b"\x1b[A\x1b[1;5C\x1b[3~\x1bOP\x1b"), vec![
+            Typed::Key(Key::Up), Typed::Key(Key::Right), Typed::Key(Key::Delete),
+            Typed::Key(Key::F1), Typed::Char('\x1b'),
+        ]);
+    }
+
+    #[test]
+    fn terminal_decode_control_OK() -> () {
+        assert_eq!(decode(b"\x01\x1bx\x03"), vec![
+            Typed::Char('a'), Typed::Char('x'), Typed::Exit,
+        ]);
+    }
+
+    #[test]
+    fn terminal_pipe_chars_OK() -> () {
+        assert_eq!(pipe_chars(b"a\x01\x03b\r\n\t\x1b[31mc\x08"), vec!['a', 'b', '\n', '\t', 'c']);
+
+        let mut source = PipeSource::new();
+        source.queue(vec!['a'; MAX_PIPE_BACKLOG - 1]);
+        source.queue(vec!['b'; 10]);
+        assert_eq!(source.backlog.len(), MAX_PIPE_BACKLOG);
+        assert_eq!(source.backlog.back(), Some(&'b'));
+    }
+}
@@ -0,0 +1,217 @@
+//! Typing text without a keyboard
+//!
+//! A Typist turns text into the KeyEvents of someone typing it on a US
+//! layout, so a pack can be heard without typing. Keys typed with
+//! alternating hands follow each other faster than keys of one hand,
+//! words and sentences end with a pause, and every interval and hold
+//! varies a little. Keys are held longer than the time between them at
+//! high speeds, so they overlap like in fast typing. Shift is held
+//! around capitals and shifted symbols and sent as FlagsChanged, like
+//! the modifiers of a `TerminalSource`.
+
+use ffi::types::{EventType, Key, KeyEvent};
+use rand::StdRng;
+use rand::distributions::{IndependentSample, Range};
+use selection;
+use std::time::Duration;
+
+/// Characters per word when counting words per minute
+const CHARS_PER_WORD: f32 = 5.0;
+/// How long a key is held on average
+const HOLD_MS: f32 = 95.0;
+/// How much intervals and holds vary, as a fraction of their length
+const JITTER: f32 = 0.25;
+/// Interval factor of keys typed with the other hand than the previous
+const ALTERNATE_HAND: f32 = 0.8;
+/// Interval factor of typing the previous key again
+const SAME_KEY: f32 = 1.3;
+/// Interval factor after a word
+const WORD_PAUSE: f32 = 1.5;
+/// Interval factor after a sentence or line
+const SENTENCE_PAUSE: f32 = 3.0;
+/// Part of the interval before a shifted key that shift goes down early
+const SHIFT_LEAD: f32 = 0.4;
+/// How long shift is held after the last shifted key is released
+const SHIFT_LAG_MS: f32 = 20.0;
+/// When the first key goes down, leaving room for shift
+const START_MS: f32 = 100.0;
+
+/// Keys typed with the left hand, the others are typed with the right
+static LEFT_HAND: &'static [Key] = &[
+    Key::Grave, Key::Digit1, Key::Digit2, Key::Digit3, Key::Digit4, Key::Digit5,
+    Key::Tab, Key::Q, Key::W, Key::E, Key::R, Key::T,
+    Key::A, Key::S, Key::D, Key::F, Key::G,
+    Key::Z, Key::X, Key::C, Key::V, Key::B,
+];
+
+
+/// Types text at a speed in words per minute
+pub struct Typist {
+    wpm: f32,
+    rng: StdRng,
+}
+
+impl Typist {
+    pub fn new(wpm: f32) -> Typist
+    {
+        Typist { wpm: wpm, rng: selection::rng(None) }
+    }
+
+    /// Creates a Typist that types the same way every time
+    pub fn with_seed(wpm: f32, seed: usize) -> Typist
+    {
+        Typist { wpm: wpm, rng: selection::rng(Some(seed)) }
+    }
+
+    /// Returns the events of typing `text`, starting shortly after time
+    /// zero. Characters without a key are skipped.
+    pub fn type_text(&mut self, text: &str) -> Vec<KeyEvent> {
+        let keys: Vec<(Key, bool)> = text.chars()
+            .filter_map(|c| {
+                let key = Key::from_char(c);
+                if key.is_none() {
+                    debug!("No key types {:?}, skipping it", c);
+                }
+                key
+            })
+            .collect();
+        if keys.is_empty() {
+            return vec![];
+        }
+
+        // intervals between presses, scaled so the text takes as long
+        // as it should at the speed
+        let mut intervals: Vec<f32> = (1..keys.len())
+            .map(|i| interval_factor(keys[i - 1].0, keys[i].0) * self.jitter())
+            .collect();
+        let total: f32 = intervals.iter().sum();
+        let ms_per_char = 60_000.0 / (self.wpm.max(1.0) * CHARS_PER_WORD);
+        for interval in intervals.iter_mut() {
+            *interval *= ms_per_char * (keys.len() - 1) as f32 / total;
+        }
+
+        let mut presses = vec![(START_MS, START_MS + HOLD_MS * self.jitter())];
+        for interval in &intervals {
+            let press = presses[presses.len() - 1].0 + interval;
+            presses.push((press, press + HOLD_MS * self.jitter()));
+        }
+
+        // a key goes up before it goes down again
+        for i in 1..keys.len() {
+            if let Some(j) = (0..i).rev().find(|&j| keys[j].0 == keys[i].0) {
+                presses[j].1 = presses[j].1.min((presses[j].0 + presses[i].0) / 2.0);
+            }
+        }
+
+        let mut events = vec![];
+        let mut shifted: Option<usize> = None;
+        for (i, &(key, shift)) in keys.iter().enumerate() {
+            match (shift, shifted) {
+                (true, None) => {
+                    let lead = match i {
+                        0 => START_MS,
+                        _ => intervals[i - 1],
+                    };
+                    events.push(event(EventType::FlagsChanged, Key::LeftShift, presses[i].0 - lead * SHIFT_LEAD));
+                    shifted = Some(i);
+                },
+                (false, Some(_)) => {
+                    events.push(event(EventType::FlagsChanged, Key::LeftShift, shift_up(&presses, i)));
+                    shifted = None;
+                },
+                _ => (),
+            }
+            events.push(event(EventType::KeyDown, key, presses[i].0));
+            events.push(event(EventType::KeyUp, key, presses[i].1));
+        }
+        if shifted.is_some() {
+            events.push(event(EventType::FlagsChanged, Key::LeftShift, shift_up(&presses, keys.len())));
+        }
+
+        events.sort_by_key(|event| event.time);
+        events
+    }
+
+    /// Returns a random factor around 1.0
+    fn jitter(&mut self) -> f32 {
+        Range::new(1.0 - JITTER, 1.0 + JITTER).ind_sample(&mut self.rng)
+    }
+}
+
+
+/// How long typing `key` after `previous` takes relative to other keys
+fn interval_factor(previous: Key, key: Key) -> f32 {
+    let left = |key| LEFT_HAND.contains(&key);
+    match previous {
+        Key::Period | Key::Enter => SENTENCE_PAUSE,
+        Key::Space => WORD_PAUSE,
+        _ if previous == key => SAME_KEY,
+        _ if left(previous) != left(key) => ALTERNATE_HAND,
+        _ => 1.0,
+    }
+}
+
+
+/// When shift goes up after the shifted keys before the `next` press,
+/// before that press if there is one
+fn shift_up(presses: &[(f32, f32)], next: usize) -> f32 {
+    let (last_press, last_release) = presses[next - 1];
+    let up = last_release + SHIFT_LAG_MS;
+    match presses.get(next) {
+        Some(&(press, _)) => up.min(press - 1.0).max(last_press + 1.0),
+        None => up,
+    }
+}
+
+
+fn event(etype: EventType, key: Key, ms: f32) -> KeyEvent {
+    let ms = ms.max(0.0);
+    KeyEvent {
+        etype: etype,
+        code: key.native_code().unwrap_or(0),
+        key: key,
+        device: 0,
+        time: Duration::new((ms / 1000.0) as u64, ((ms % 1000.0) * 1_000_000.0) as u32),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::Typist;
+    use ffi::types::{EventType, Key};
+    use std::time::Duration;
+
+    #[test]
+    fn typist_type_text_OK() -> () {
+        let events = Typist::with_seed(60.0, 7).type_text("Hi, all.\nOK");
+        let keys: Vec<(EventType, Key)> = events.iter().map(|event| (event.etype, event.key)).collect();
+        let time = |etype, key| events.iter().find(|event| event.etype == etype && event.key == key).unwrap().time;
+
+        // shift around the capitals only
+        let shifts: Vec<_> = events.iter().filter(|event| event.key == Key::LeftShift).map(|event| event.time).collect();
+        assert_eq!(shifts.len(), 4);
+        assert!(shifts[0] < time(EventType::KeyDown, Key::H));
+        assert!(shifts[1] > time(EventType::KeyUp, Key::H) && shifts[1] < time(EventType::KeyDown, Key::I));
+        assert!(shifts[2] < time(EventType::KeyDown, Key::O) && shifts[3] > time(EventType::KeyUp, Key::K));
+        assert!(keys.contains(&(EventType::KeyDown, Key::Space)));
+        assert!(keys.contains(&(EventType::KeyDown, Key::Enter)));
+
+        // the double l goes up before it goes down again
+        let l: Vec<_> = keys.iter().filter(|k| k.1 == Key::L).map(|k| k.0).collect();
+        assert_eq!(l, vec![EventType::KeyDown, EventType::KeyUp, EventType::KeyDown, EventType::KeyUp]);
+
+        // 11 characters at 5 per second
+        let last_press = events.iter().filter(|event| event.etype == EventType::KeyDown).last().unwrap();
+        assert_eq!(last_press.time, events.iter().find(|event| event.key == Key::K).unwrap().time);
+        assert!(last_press.time > Duration::from_millis(2000) && last_press.time < Duration::from_millis(2200));
+        assert!(events.windows(2).all(|pair| pair[0].time <= pair[1].time));
+
+        let same = Typist::with_seed(60.0, 7).type_text("Hi, all.\nOK");
+        assert_eq!(same.iter().map(|event| event.time).collect::<Vec<_>>(),
+                   events.iter().map(|event| event.time).collect::<Vec<_>>());
+        assert!(Typist::new(60.0).type_text("ü").is_empty());
+    }
+}
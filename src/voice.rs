@@ -0,0 +1,273 @@
+//! Voices for overlapping clicks
+//!
+//! A voice (an OpenAL source) plays one sample at a time. Restarting a
+//! voice that is still playing cuts its click off, so fast typing needs
+//! several voices per sample. The VoicePool loads every sample once and
+//! hands out voices that share its data, up to a maximum polyphony, and
+//! steals a playing voice when all are busy.
+
+use audio::{AudioBackend, SampleId, Voice};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use ::errors::KeyboardError;
+
+/// How many voices play at most by default
+pub const DEFAULT_MAX_VOICES: usize = 32;
+
+
+/// A sample loaded by the backend of a VoicePool
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: String,
+    id: SampleId,
+}
+
+
+/// Which voice to cut off when all of them are playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StealPolicy {
+    /// The voice that started first
+    Oldest,
+    /// The voice playing at the lowest volume
+    Quietest,
+    /// None, the new click is dropped
+    Never,
+}
+
+impl FromStr for StealPolicy {
+    type Err = KeyboardError;
+
+    fn from_str(s: &str) -> Result<StealPolicy, KeyboardError> {
+        match s {
+            "oldest" => Ok(StealPolicy::Oldest),
+            "quietest" => Ok(StealPolicy::Quietest),
+            "never" => Ok(StealPolicy::Never),
+            _ => Err(KeyboardError::Config(format!(
+                "voice_stealing must be one of oldest, quietest or never, not {}", s))),
+        }
+    }
+}
+
+
+/// A voice and what it last played
+struct Slot {
+    voice: Box<dyn Voice>,
+    sample: SampleId,
+    /// When the voice last started, counted in plays
+    started: u64,
+    volume: f32,
+}
+
+
+/// A limited set of voices that play samples
+pub struct VoicePool {
+    backend: Box<dyn AudioBackend>,
+    samples: HashMap<PathBuf, Sample>,
+    slots: Vec<Slot>,
+    max_voices: usize,
+    steal: StealPolicy,
+    plays: u64,
+}
+
+impl VoicePool {
+    pub fn new(backend: Box<dyn AudioBackend>) -> VoicePool
+    {
+        VoicePool {
+            backend: backend,
+            samples: HashMap::new(),
+            slots: vec![],
+            max_voices: DEFAULT_MAX_VOICES,
+            steal: StealPolicy::Oldest,
+            plays: 0,
+        }
+    }
+
+    /// Sets how many voices play at once and which to steal when all
+    /// are busy
+    pub fn set_polyphony(&mut self, max_voices: usize, steal: StealPolicy) {
+        self.max_voices = max_voices;
+        self.steal = steal;
+        self.slots.truncate(max_voices);
+    }
+
+    /// Loads the sound file at `path`, files are only loaded once
+    pub fn load(&mut self, path: &Path) -> Result<Sample, KeyboardError> {
+        if let Some(sample) = self.samples.get(path) {
+            return Ok(sample.clone());
+        }
+
+        let name = try!(path.file_name()
+            .ok_or(format!("Unable to parse filename: {:?}", path))
+            .and_then(|n| Ok(n.to_string_lossy().to_string())));
+
+        let sample = Sample { name: name, id: try!(self.backend.load(path)) };
+        self.samples.insert(path.to_path_buf(), sample.clone());
+        Ok(sample)
+    }
+
+    /// Places the listener
+    pub fn set_listener(&mut self, position: [f32; 3], at: [f32; 3], up: [f32; 3]) {
+        self.backend.set_listener(position, at, up);
+    }
+
+    /// Plays `sample` on a free voice, or on a stolen one if all are
+    /// busy. Drops the click if there is no voice to play it on.
+    pub fn play(&mut self, sample: &Sample, position: [f32; 3], pitch: f32, volume: f32) {
+        let index = match self.slot_for(sample) {
+            Some(index) => index,
+            None => return debug!("No voice for {}, dropping it", sample.name),
+        };
+
+        let slot = &mut self.slots[index];
+        slot.started = self.plays;
+        slot.volume = volume;
+        self.plays += 1;
+
+        slot.voice.set_position(position);
+        slot.voice.set_pitch(pitch);
+        slot.voice.set_volume(volume);
+        debug!("Playing {} on voice {}", sample.name, index);
+        slot.voice.play();
+    }
+
+    /// Creates a slot with a voice for `sample`
+    fn new_slot(&mut self, sample: &Sample) -> Result<Slot, KeyboardError> {
+        Ok(Slot {
+            voice: try!(self.backend.voice(sample.id)),
+            sample: sample.id,
+            started: 0,
+            volume: 0.0,
+        })
+    }
+
+    /// Returns the index of a slot that is ready to play `sample`
+    fn slot_for(&mut self, sample: &Sample) -> Option<usize> {
+        // an idle voice that has the sample already
+        let idle = self.slots.iter()
+            .position(|slot| !slot.voice.is_playing() && slot.sample == sample.id);
+        if idle.is_some() {
+            return idle;
+        }
+
+        // a new voice
+        if self.slots.len() < self.max_voices {
+            match self.new_slot(sample) {
+                Ok(slot) => {
+                    self.slots.push(slot);
+                    return Some(self.slots.len() - 1);
+                },
+                Err(error) => {
+                    warn!("Unable to create voice {}, limiting polyphony to {}: {:?}",
+                          self.slots.len() + 1, self.slots.len(), error);
+                    self.max_voices = self.slots.len();
+                },
+            }
+        }
+
+        // an idle voice of another sample or a stolen one, replaced by a
+        // voice of the sample after the old one is freed
+        let index = match self.slots.iter().position(|slot| !slot.voice.is_playing()) {
+            Some(index) => index,
+            None => match self.victim() {
+                Some(index) => index,
+                None => return None,
+            },
+        };
+        self.slots[index].voice.stop();
+        self.slots.swap_remove(index);
+
+        match self.new_slot(sample) {
+            Ok(slot) => {
+                self.slots.push(slot);
+                Some(self.slots.len() - 1)
+            },
+            Err(error) => {
+                warn!("Unable to replace voice: {:?}", error);
+                self.max_voices = self.slots.len();
+                None
+            },
+        }
+    }
+
+    /// Returns the index of the playing voice to steal
+    fn victim(&self) -> Option<usize> {
+        let slots = self.slots.iter().enumerate();
+        match self.steal {
+            StealPolicy::Oldest => slots.min_by_key(|&(_, slot)| slot.started).map(|(i, _)| i),
+            StealPolicy::Quietest => slots
+                .fold(None, |quietest: Option<(usize, f32)>, (i, slot)| match quietest {
+                    Some((_, volume)) if volume <= slot.volume => quietest,
+                    _ => Some((i, slot.volume)),
+                })
+                .map(|(i, _)| i),
+            StealPolicy::Never => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use super::{StealPolicy, VoicePool};
+    use audio::{MockBackend, MockEvent};
+    use std::path::Path;
+
+    #[test]
+    fn voice_steal_policy_OK() -> () {
+        assert_eq!("oldest".parse::<StealPolicy>().unwrap(), StealPolicy::Oldest);
+        assert_eq!("quietest".parse::<StealPolicy>().unwrap(), StealPolicy::Quietest);
+        assert_eq!("never".parse::<StealPolicy>().unwrap(), StealPolicy::Never);
+    }
+
+    #[test]
+    fn voice_steal_policy_ERR() -> () {
+        assert!("newest".parse::<StealPolicy>().is_err());
+        assert!("".parse::<StealPolicy>().is_err());
+    }
+
+    #[test]
+    fn voice_pool_steal_OK() -> () {
+        let backend = MockBackend::new().with_max_voices(2);
+        let log = backend.log();
+        let mut pool = VoicePool::new(Box::new(backend));
+        pool.set_polyphony(4, StealPolicy::Oldest);
+
+        let a = pool.load(Path::new("a.wav")).unwrap();
+        let b = pool.load(Path::new("b.wav")).unwrap();
+        pool.load(Path::new("a.wav")).unwrap();
+
+        // overlapping plays of one sample, the third steals the first
+        pool.play(&a, [0.0; 3], 1.0, 1.0);
+        pool.play(&a, [0.0; 3], 1.0, 0.5);
+        pool.play(&b, [0.0; 3], 1.0, 1.0);
+
+        let play = |sample: &str, volume| MockEvent::Play {
+            sample: sample.into(), position: [0.0; 3], pitch: 1.0, volume: volume
+        };
+        assert_eq!(*log.borrow(), vec![
+            MockEvent::Load("a.wav".into()),
+            MockEvent::Load("b.wav".into()),
+            play("a.wav", 1.0),
+            play("a.wav", 0.5),
+            MockEvent::Stop("a.wav".into()),
+            play("b.wav", 1.0),
+        ]);
+    }
+
+    #[test]
+    fn voice_pool_never_steal_OK() -> () {
+        let backend = MockBackend::new();
+        let log = backend.log();
+        let mut pool = VoicePool::new(Box::new(backend));
+        pool.set_polyphony(1, StealPolicy::Never);
+
+        let a = pool.load(Path::new("a.wav")).unwrap();
+        pool.play(&a, [0.0; 3], 1.0, 1.0);
+        pool.play(&a, [0.0; 3], 1.0, 1.0);
+        assert_eq!(log.borrow().len(), 2);
+        assert!("loudest".parse::<StealPolicy>().is_err());
+    }
+}